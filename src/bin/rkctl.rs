@@ -0,0 +1,58 @@
+use std::env::args;
+use std::error::Error;
+use std::io::{copy, stdout, BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::process::exit;
+
+#[path = "../socket.rs"]
+mod socket;
+
+use socket::socket_paths;
+
+const USAGE: &str = "Usage: rkctl <command>
+
+Commands:
+  status                       Show enabled state, flags, profiles, layers & LEDs
+  enable | disable | toggle    Turn remapping on or off
+  layer on|off|toggle <name>   Switch a layer
+  profile <device> <profile>   Use a profile for a device, \"default\" for the top-level mappings
+                               or \"auto\" to pick one by device again
+  reload                       Reload the config
+  devices                      List grabbed devices
+  rules [device]               List mapping rules
+  set-context [app]            Set the focused app for [app.*] mappings, none if left out
+  watch                        Print the status as a JSON line whenever it changes";
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = args().skip(1).collect();
+    if args.is_empty() || args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    // rk falls back to $XDG_RUNTIME_DIR when it can't create the default socket
+    let paths = socket_paths();
+    let path = paths.iter().find(|p| p.exists()).unwrap_or(&paths[0]);
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| format!("Failed to connect to rk at {}: {}", path.display(), e))?;
+
+    // Tabs keep arguments with spaces, like device names, intact
+    stream.write_all(format!("{}\n", args.join("\t")).as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    // Replies are streamed, `watch` never ends
+    let mut reply = BufReader::new(stream);
+    let mut first = String::new();
+    reply.read_line(&mut first)?;
+
+    if let Some(e) = first.strip_prefix("error: ") {
+        eprint!("Error: {}", e);
+        exit(1);
+    }
+    let mut out = stdout();
+    out.write_all(first.as_bytes())?;
+    out.flush()?;
+    copy(&mut reply, &mut out)?;
+    Ok(())
+}
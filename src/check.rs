@@ -0,0 +1,720 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::read_to_string;
+use std::ops::Range;
+
+use evdev::KeyCode;
+use toml::de::{DeString, DeTable, DeValue};
+use toml::Spanned;
+
+use crate::device::globs_overlap;
+use crate::layout::{Layout, TextConfig};
+use crate::{
+    parse_combo, parse_conditions, parse_led, parse_mapping, Action, Condition, Config, Modifier,
+    Profile, Sections, Target,
+};
+
+struct Diagnostic {
+    span: Range<usize>,
+    error: bool,
+    message: String,
+}
+
+/// A valid mapping, kept to compare against the others
+struct CheckedRule {
+    scope: String,
+    /// The app pattern of `[app.*]` rules
+    app: Option<String>,
+    keys: Vec<KeyCode>,
+    conditions: Vec<(Condition, bool)>,
+    action: Action,
+    span: Range<usize>,
+}
+
+impl CheckedRule {
+    /// The conditions besides the focused app, which the scopes tell apart
+    fn state_conditions(&self) -> Vec<(Condition, bool)> {
+        self.conditions
+            .iter()
+            .filter(|(condition, _)| !matches!(condition, Condition::App(_)))
+            .copied()
+            .collect()
+    }
+
+    /// True if there are LED & flag states in which both rules apply
+    fn overlaps(&self, other: &CheckedRule) -> bool {
+        !self
+            .conditions
+            .iter()
+            .any(|(condition, on)| other.conditions.contains(&(*condition, !on)))
+    }
+}
+
+struct Checker<'a> {
+    path: &'a str,
+    content: &'a str,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Validates a config without opening any devices, returns true if it has no errors
+pub fn run(path: &str) -> Result<bool, Box<dyn Error>> {
+    let content = read_to_string(path)?;
+    let mut checker = Checker {
+        path,
+        content: &content,
+        diagnostics: Vec::new(),
+    };
+    checker.diagnose()?;
+    Ok(checker.report())
+}
+
+/// True for a `vendor:product` pair of 4-digit hex IDs, like 046d:c31c
+fn is_usb_id(id: &str) -> bool {
+    id.split_once(':').is_some_and(|(vendor, product)| {
+        [vendor, product]
+            .iter()
+            .all(|part| part.len() == 4 && part.chars().all(|c| c.is_ascii_hexdigit()))
+    })
+}
+
+fn get<'t, 'i>(
+    table: &'t DeTable<'i>,
+    name: &str,
+) -> Option<(&'t Spanned<DeString<'i>>, &'t Spanned<DeValue<'i>>)> {
+    table.iter().find(|(key, _)| key.get_ref() == name)
+}
+
+/// A `[mappings.*]` section & the sections nested in it, with their full names
+type Section<'t, 'i> = (String, Range<usize>, &'t Spanned<DeValue<'i>>);
+
+/// Collects `section` & the sections nested in it, innermost first
+fn nested_sections<'t, 'i>(
+    section: Section<'t, 'i>,
+    mappings: &HashMap<String, HashMap<String, Target>>,
+    sections: &mut Vec<Section<'t, 'i>>,
+) {
+    let (name, _, table) = &section;
+    for (key, value) in entries(table) {
+        let nested = format!("{}.{}", name, key.get_ref());
+        let is_target = mappings[name].contains_key(key.get_ref().as_ref());
+        if !is_target && mappings.contains_key(&nested) {
+            nested_sections((nested, key.span(), value), mappings, sections);
+        }
+    }
+    sections.push(section);
+}
+
+fn entries<'t, 'i>(
+    value: &'t Spanned<DeValue<'i>>,
+) -> impl Iterator<Item = (&'t Spanned<DeString<'i>>, &'t Spanned<DeValue<'i>>)> {
+    value
+        .get_ref()
+        .as_table()
+        .into_iter()
+        .flat_map(|t| t.iter())
+}
+
+impl Checker<'_> {
+    fn error(&mut self, span: Range<usize>, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            span,
+            error: true,
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, span: Range<usize>, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            span,
+            error: false,
+            message: message.into(),
+        });
+    }
+
+    fn line_col(&self, offset: usize) -> (usize, usize) {
+        let before = &self.content[..offset.min(self.content.len())];
+        let line = before.matches('\n').count() + 1;
+        let col = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        (line, col)
+    }
+
+    /// Collects the diagnostics for the whole config
+    fn diagnose(&mut self) -> Result<(), Box<dyn Error>> {
+        match toml::from_str::<Config>(self.content) {
+            Ok(config) => self.check(&config)?,
+            Err(e) => self.error(e.span().unwrap_or(0..0), e.message()),
+        }
+        self.diagnostics.sort_by_key(|d| d.span.start);
+        Ok(())
+    }
+
+    fn check(&mut self, config: &Config) -> Result<(), Box<dyn Error>> {
+        let doc = DeTable::parse(self.content)?;
+        let doc = doc.get_ref();
+
+        let toggle = match parse_combo(&config.toggle) {
+            Ok(toggle) => Some(toggle),
+            Err(e) => {
+                let span = get(doc, "toggle").map_or(0..0, |(_, v)| v.span());
+                self.error(span, format!("Invalid toggle: {}", e));
+                None
+            }
+        };
+
+        let layout = Layout::new(&config.text).unwrap_or_else(|e| {
+            let span = get(doc, "text").map_or(0..0, |(k, _)| k.span());
+            self.error(span, e.to_string());
+            Layout::new(&TextConfig::default()).expect("default text config is valid")
+        });
+
+        let flag_names: Vec<String> = config.flags.keys().cloned().collect();
+        for (flag, _) in get(doc, "flags").into_iter().flat_map(|(_, v)| entries(v)) {
+            if parse_led(flag.get_ref()).is_some() {
+                self.warning(
+                    flag.span(),
+                    format!(
+                        "Flag {} hides the LED of the same name in conditions",
+                        flag.get_ref()
+                    ),
+                );
+            }
+        }
+
+        let mut rules = self.check_keymap(
+            doc,
+            &config.mappings,
+            &config.layers,
+            &config.app,
+            &flag_names,
+            &layout,
+        );
+        self.check_rules(&mut rules, toggle);
+
+        for (key, value) in get(doc, "device").into_iter().flat_map(|(_, v)| entries(v)) {
+            let profiles = &config.device;
+            match key.get_ref().as_ref() {
+                "usb" => {
+                    for (id, value) in entries(value) {
+                        if !is_usb_id(id.get_ref()) {
+                            self.error(
+                                id.span(),
+                                format!(
+                                    "Invalid USB ID: {}, expected vendor:product",
+                                    id.get_ref()
+                                ),
+                            );
+                        }
+                        let profile = &profiles.usb[id.get_ref().as_ref()];
+                        self.check_profile(value, profile, config, &layout);
+                    }
+                }
+                "path" => {
+                    for (path, value) in entries(value) {
+                        let profile = &profiles.path[path.get_ref().as_ref()];
+                        self.check_profile(value, profile, config, &layout);
+                    }
+                }
+                name => {
+                    let profile = &profiles.name[name];
+                    self.check_profile(value, profile, config, &layout);
+                }
+            }
+        }
+
+        let leds = [
+            ("feedback", Some(&config.feedback.led)),
+            ("indicator", config.indicator.led.as_ref()),
+        ];
+        for (section, led) in leds {
+            let Some(led) = led.filter(|led| parse_led(led).is_none()) else {
+                continue;
+            };
+            let table = get(doc, section).and_then(|(_, v)| v.get_ref().as_table());
+            let span = table
+                .and_then(|t| get(t, "led"))
+                .map_or(0..0, |(_, v)| v.span());
+            self.error(span, format!("Invalid {} LED: {}", section, led));
+        }
+
+        Ok(())
+    }
+
+    /// Checks a `[device.*]` profile the way its keyboards would load it
+    fn check_profile(
+        &mut self,
+        table: &Spanned<DeValue>,
+        profile: &Profile,
+        config: &Config,
+        layout: &Layout,
+    ) {
+        let Some(doc) = table.get_ref().as_table() else {
+            return;
+        };
+
+        let toggle = match &profile.toggle {
+            Some(toggle) => match parse_combo(toggle) {
+                Ok(toggle) => Some(toggle),
+                Err(e) => {
+                    let span = get(doc, "toggle").map_or(0..0, |(_, v)| v.span());
+                    self.error(span, format!("Invalid toggle: {}", e));
+                    None
+                }
+            },
+            None => parse_combo(&config.toggle).ok(),
+        };
+
+        let flag_names: Vec<String> = config.flags.keys().cloned().collect();
+        let mut rules = self.check_keymap(
+            doc,
+            &profile.mappings,
+            &profile.layers,
+            &profile.app,
+            &flag_names,
+            layout,
+        );
+        self.check_rules(&mut rules, toggle);
+    }
+
+    /// Checks the `mappings`, `app` & `layers` tables of `doc`, collecting the valid rules
+    fn check_keymap(
+        &mut self,
+        doc: &DeTable,
+        config_mappings: &HashMap<String, HashMap<String, Target>>,
+        config_layers: &HashMap<String, HashMap<String, Target>>,
+        config_apps: &BTreeMap<String, Sections>,
+        flag_names: &[String],
+        layout: &Layout,
+    ) -> Vec<CheckedRule> {
+        let layer_names: Vec<String> = config_layers.keys().cloned().collect();
+        let app_names: Vec<String> = config_apps.keys().cloned().collect();
+        let mut rules = Vec::new();
+
+        // Sections with their scope, app & the mappings they were read into
+        let mut sections = Vec::new();
+        for (name, table) in get(doc, "mappings")
+            .into_iter()
+            .flat_map(|(_, v)| entries(v))
+        {
+            let mut nested = Vec::new();
+            let section = (name.get_ref().to_string(), name.span(), table);
+            nested_sections(section, config_mappings, &mut nested);
+            sections.extend(
+                nested
+                    .into_iter()
+                    .map(|s| ("mappings".to_string(), None, config_mappings, s)),
+            );
+        }
+        for (name, table) in get(doc, "app").into_iter().flat_map(|(_, v)| entries(v)) {
+            let app = app_names.iter().position(|a| a == name.get_ref().as_ref());
+            let app_mappings = &config_apps[name.get_ref().as_ref()];
+            let scope = format!("app.\"{}\"", name.get_ref());
+
+            let mut nested = Vec::new();
+            nested_sections(
+                ("default".into(), name.span(), table),
+                app_mappings,
+                &mut nested,
+            );
+            sections.extend(
+                nested
+                    .into_iter()
+                    .map(|s| (scope.clone(), app, app_mappings, s)),
+            );
+        }
+
+        for (scope, app, config_mappings, (section, section_span, mappings)) in sections {
+            let mut conditions = match parse_conditions(&section, flag_names) {
+                Ok(conditions) => conditions,
+                Err(e) => {
+                    self.error(section_span, e);
+                    continue;
+                }
+            };
+            conditions.extend(app.map(|app| (Condition::App(app), true)));
+
+            if let Some((condition, _)) = conditions
+                .iter()
+                .find(|(condition, on)| conditions.contains(&(*condition, !on)))
+            {
+                self.warning(
+                    section_span.clone(),
+                    format!(
+                        "Section never applies, {} is required both on and off",
+                        condition.name(flag_names, &app_names)
+                    ),
+                );
+            }
+
+            let targets = &config_mappings[&section];
+            for (from, to) in entries(mappings) {
+                // Nested sections are checked on their own
+                let Some(target) = targets.get(from.get_ref().as_ref()) else {
+                    continue;
+                };
+                let span = from.span().start..to.span().end;
+
+                match parse_mapping(from.get_ref(), target, &layer_names, flag_names, layout) {
+                    Ok((mut keys, modifiers, action)) => {
+                        keys.sort();
+                        let mut conditions = conditions.clone();
+                        conditions.extend(
+                            modifiers
+                                .into_iter()
+                                .map(|m| (Condition::Modifier(m), true)),
+                        );
+                        rules.push(CheckedRule {
+                            scope: scope.clone(),
+                            app: app.map(|app| app_names[app].clone()),
+                            keys,
+                            conditions,
+                            action,
+                            span,
+                        });
+                    }
+                    Err(e) => self.error(span, e),
+                }
+            }
+        }
+
+        for (name, mappings) in get(doc, "layers").into_iter().flat_map(|(_, v)| entries(v)) {
+            let targets = &config_layers[name.get_ref().as_ref()];
+            for (from, to) in entries(mappings) {
+                let target = &targets[from.get_ref().as_ref()];
+                let span = from.span().start..to.span().end;
+
+                match parse_mapping(from.get_ref(), target, &layer_names, flag_names, layout) {
+                    Ok((mut keys, modifiers, action)) => {
+                        keys.sort();
+                        rules.push(CheckedRule {
+                            scope: format!("layers.{}", name.get_ref()),
+                            app: None,
+                            keys,
+                            conditions: modifiers
+                                .into_iter()
+                                .map(|m| (Condition::Modifier(m), true))
+                                .collect(),
+                            action,
+                            span,
+                        });
+                    }
+                    Err(e) => self.error(span, e),
+                }
+            }
+        }
+
+        rules
+    }
+
+    /// Reports rules that duplicate, conflict with or are shadowed by others
+    fn check_rules(&mut self, rules: &mut [CheckedRule], toggle: Option<(Vec<KeyCode>, KeyCode)>) {
+        rules.sort_by_key(|r| r.span.start);
+        for rule in rules.iter_mut() {
+            rule.conditions
+                .sort_by_key(|(condition, on)| (condition.sort_key(), *on));
+            rule.conditions.dedup();
+        }
+
+        for (i, rule) in rules.iter().enumerate() {
+            if let Some((mods, key)) = &toggle {
+                // The toggle also wins over rules requiring the modifiers it's pressed with
+                let held = |m: &KeyCode| {
+                    Modifier::of(*m)
+                        .is_some_and(|m| rule.conditions.contains(&(Condition::Modifier(m), true)))
+                };
+                if rule.keys == [*key] && mods.iter().all(held) {
+                    self.warning(rule.span.clone(), "Shadowed by the toggle key");
+                }
+            }
+
+            // Sections requiring an LED both on and off never apply, that's reported already
+            if !rule.overlaps(rule) {
+                continue;
+            }
+
+            // App sections win ties with [mappings.*] ones, so only rules from the same scope
+            // or from apps some window matches both of can be ambiguous
+            for earlier in &rules[..i] {
+                let apps_overlap = matches!(
+                    (&earlier.app, &rule.app),
+                    (Some(a), Some(b)) if globs_overlap(a, b)
+                );
+                if (earlier.scope != rule.scope && !apps_overlap)
+                    || earlier.keys != rule.keys
+                    || !rule.overlaps(earlier)
+                {
+                    continue;
+                }
+
+                let (line, col) = self.line_col(earlier.span.start);
+                if earlier.state_conditions() == rule.state_conditions() {
+                    if earlier.action == rule.action {
+                        self.warning(
+                            rule.span.clone(),
+                            format!("Duplicate of the mapping at {}:{}", line, col),
+                        );
+                    } else {
+                        self.error(
+                            rule.span.clone(),
+                            format!("Conflicts with the mapping at {}:{}", line, col),
+                        );
+                    }
+                    break;
+                }
+
+                // Rules with more conditions win, so only equally specific ones are ambiguous
+                if earlier.conditions.len() == rule.conditions.len()
+                    && earlier.action != rule.action
+                {
+                    self.warning(
+                        rule.span.clone(),
+                        format!(
+                            "Overlaps with the mapping at {}:{}, which one applies is unspecified",
+                            line, col
+                        ),
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Prints all diagnostics, returns true if there were no errors
+    fn report(&self) -> bool {
+        for d in &self.diagnostics {
+            let (line, col) = self.line_col(d.span.start);
+            let level = if d.error { "error" } else { "warning" };
+            println!("{}:{}:{}: {}: {}", self.path, line, col, level, d.message);
+        }
+
+        let errors = self.diagnostics.iter().filter(|d| d.error).count();
+        let warnings = self.diagnostics.len() - errors;
+        println!("{}: {} errors, {} warnings", self.path, errors, warnings);
+
+        errors == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    /// The diagnostics for `content`, as `line:col: level: message`
+    fn check(content: &str) -> Vec<String> {
+        let mut checker = Checker {
+            path: "rk.toml",
+            content,
+            diagnostics: Vec::new(),
+        };
+        checker.diagnose().unwrap();
+        checker
+            .diagnostics
+            .iter()
+            .map(|d| {
+                let (line, col) = checker.line_col(d.span.start);
+                let level = if d.error { "error" } else { "warning" };
+                format!("{}:{}: {}: {}", line, col, level, d.message)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn run_fails_on_errors_only() {
+        let path = std::env::temp_dir().join(format!("rk-check-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        write(path, "toggle = \"ctrl+f12\"\n[flags]\nnumlock = false\n").unwrap();
+        assert!(run(path).unwrap());
+        write(path, "toggle = \"ctrl+nope\"\n").unwrap();
+        assert!(!run(path).unwrap());
+        remove_file(path).unwrap();
+
+        assert!(run(path).is_err());
+    }
+
+    #[test]
+    fn valid_config_has_no_diagnostics() {
+        let config = r#"
+toggle = "ctrl+alt+r"
+
+[mappings.default]
+capslock = "esc"
+"j+k" = "esc"
+"shift+backspace" = "delete"
+
+[mappings.numlock_off]
+kp8 = "up"
+
+[layers.nav]
+h = "left"
+"#;
+        assert_eq!(check(config), Vec::<String>::new());
+    }
+
+    #[test]
+    fn syntax_errors_are_positioned() {
+        assert_eq!(
+            check("toggle = \"ctrl+f12\"\n[mappings.default]\na = \n"),
+            ["3:5: error: string values must be quoted, expected literal string"]
+        );
+    }
+
+    #[test]
+    fn invalid_mappings_are_errors() {
+        let config = r#"toggle = "ctrl+f12"
+[mappings.default]
+nope = "esc"
+a = "layer_hold(missing)"
+
+[mappings.nope_on]
+b = "c"
+"#;
+        assert_eq!(
+            check(config),
+            [
+                "3:1: error: Invalid key: nope",
+                "4:1: error: Unknown layer: missing",
+                "6:11: error: Unknown LED or flag: nope",
+            ]
+        );
+    }
+
+    #[test]
+    fn conflicting_and_duplicate_mappings() {
+        let config = r#"toggle = "ctrl+f12"
+[mappings.default]
+"shift+a" = "b"
+"shift+c" = "d"
+
+[mappings.when_shift]
+a = "x"
+c = "d"
+"#;
+        assert_eq!(
+            check(config),
+            [
+                "7:1: error: Conflicts with the mapping at 3:1",
+                "8:1: warning: Duplicate of the mapping at 4:1",
+            ]
+        );
+    }
+
+    #[test]
+    fn equally_specific_sections_overlap() {
+        let config = r#"toggle = "ctrl+f12"
+[mappings.numlock_off]
+a = "b"
+
+[mappings.capslock_on]
+a = "c"
+
+# Never applies together with numlock_off, only capslock_on
+[mappings.numlock_on]
+a = "d"
+"#;
+        assert_eq!(
+            check(config),
+            [
+                "6:1: warning: Overlaps with the mapping at 3:1, which one applies is unspecified",
+                "10:1: warning: Overlaps with the mapping at 6:1, which one applies is unspecified",
+            ]
+        );
+    }
+
+    #[test]
+    fn app_sections_win_ties_with_mappings() {
+        let config = r#"toggle = "ctrl+f12"
+[mappings.numlock_off]
+a = "b"
+
+[app."firefox"]
+a = "c"
+
+[app."*term*"]
+a = "d"
+"#;
+        assert_eq!(check(config), Vec::<String>::new());
+    }
+
+    #[test]
+    fn sections_that_never_apply_and_shadowed_keys() {
+        let config = r#"toggle = "ctrl+f12"
+[mappings.when_ctrl]
+f12 = "f1"
+
+[mappings.numlock_on.numlock_off]
+a = "b"
+"#;
+        assert_eq!(
+            check(config),
+            [
+                "3:1: warning: Shadowed by the toggle key",
+                "5:22: warning: Section never applies, numlock is required both on and off",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_device_and_led_settings() {
+        let config = r#"toggle = "ctrl+f12"
+[device.usb."046d"]
+toggle = "ctrl+f11"
+
+[feedback]
+led = "nope"
+"#;
+        assert_eq!(
+            check(config),
+            [
+                "2:13: error: Invalid USB ID: 046d, expected vendor:product",
+                "6:7: error: Invalid feedback LED: nope",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        let diagnostics = check("toggle = \"ctrl+f12\"\n[mapings.default]\na = \"b\"\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].starts_with("2:2: error: unknown field `mapings`"));
+    }
+
+    #[test]
+    fn unknown_device_keys_are_errors() {
+        let diagnostics = check("toggle = \"ctrl+f12\"\n[device.\"Foo\"]\nmapings = 3\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].starts_with("2:2: error: unknown field `mapings`"));
+    }
+
+    #[test]
+    fn apps_matched_by_both_globs_can_conflict() {
+        let config = r#"toggle = "ctrl+f12"
+[app."fire*"]
+a = "b"
+
+[app."firefox"]
+a = "c"
+
+[app."chrom*"]
+a = "d"
+"#;
+        assert_eq!(
+            check(config),
+            ["6:1: error: Conflicts with the mapping at 3:1"]
+        );
+
+        // when_meta is the same condition as the meta shorthand
+
+        let config = r#"toggle = "ctrl+f12"
+[app."fire*"]
+"meta+c" = "d"
+
+[app."*fox".when_meta]
+c = "d"
+"#;
+        assert_eq!(
+            check(config),
+            ["6:1: warning: Duplicate of the mapping at 3:1"]
+        );
+    }
+}
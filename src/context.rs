@@ -0,0 +1,648 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::env::var;
+use std::error::Error;
+use std::fs::read_dir;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
+use nix::unistd::{geteuid, Uid, User};
+
+use crate::notify::session_uid;
+
+/// Where the focused application comes from
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// The first of sway/i3, Hyprland & X11 that's running
+    #[default]
+    Auto,
+    /// sway or i3
+    Sway,
+    Hyprland,
+    X11,
+    /// Only `rkctl set-context`
+    None,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ContextConfig {
+    provider: Provider,
+}
+
+/// Follows the focused application, for `[app.*]` mappings
+///
+/// Reconnects every `RETRY` while there's no provider, as rk may start before the compositor,
+/// which may also restart.
+pub struct ContextWatcher {
+    /// Providers to try, in order
+    providers: Vec<Provider>,
+    /// Whether failing to connect is expected, as with `auto`
+    quiet: bool,
+    /// The provider's socket, found from the environment when None
+    socket: Option<PathBuf>,
+    source: Option<Source>,
+    /// Data read but not parsed yet
+    buf: Vec<u8>,
+    /// A running lookup of the focused window
+    query: Option<Query>,
+    /// Wakes on provider & lookup output, it's what the main loop waits on
+    epoll: Epoll,
+    /// When to try connecting again
+    retry: Option<Instant>,
+}
+
+enum Source {
+    /// sway/i3 IPC, the tree is fetched on every window & workspace event
+    I3(UnixStream),
+    /// Hyprland's event socket, `activewindow>>class,title` lines
+    Hyprland(UnixStream),
+    /// `xprop -spy` printing the active window whenever it changes
+    X11 { xprop: Child, stdout: UnixStream },
+}
+
+impl Source {
+    fn stream(&mut self) -> &mut UnixStream {
+        match self {
+            Source::I3(stream) | Source::Hyprland(stream) => stream,
+            Source::X11 { stdout, .. } => stdout,
+        }
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        if let Source::X11 { xprop, .. } = self {
+            let _ = xprop.kill();
+            let _ = xprop.wait();
+        }
+    }
+}
+
+/// A lookup of the focused window, read as its output arrives
+struct Query {
+    stream: UnixStream,
+    output: Vec<u8>,
+    /// `xprop -id <window> WM_CLASS` on X11, None for Hyprland's `j/activewindow`
+    xprop: Option<Child>,
+}
+
+impl Drop for Query {
+    fn drop(&mut self) {
+        if let Some(xprop) = &mut self.xprop {
+            let _ = xprop.kill();
+            let _ = xprop.wait();
+        }
+    }
+}
+
+const I3_MAGIC: &[u8] = b"i3-ipc";
+const I3_SUBSCRIBE: u32 = 2;
+const I3_GET_TREE: u32 = 4;
+/// Events have the high bit set
+const I3_EVENT: u32 = 1 << 31;
+
+const RETRY: Duration = Duration::from_secs(5);
+
+impl ContextWatcher {
+    pub fn new(config: &ContextConfig) -> Result<Self, Box<dyn Error>> {
+        Self::with_socket(config, None)
+    }
+
+    /// A watcher connecting to the provider at `socket`, a directory for Hyprland
+    fn with_socket(
+        config: &ContextConfig,
+        socket: Option<PathBuf>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let providers = match config.provider {
+            Provider::Auto => vec![Provider::Sway, Provider::Hyprland, Provider::X11],
+            Provider::None => Vec::new(),
+            provider => vec![provider],
+        };
+
+        let mut watcher = Self {
+            providers,
+            quiet: config.provider == Provider::Auto,
+            socket,
+            source: None,
+            buf: Vec::new(),
+            query: None,
+            epoll: Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?,
+            retry: None,
+        };
+        if !watcher.connect(true) && !watcher.providers.is_empty() {
+            info!(
+                "No focused app provider yet, trying again every {:?}",
+                RETRY
+            );
+        }
+        Ok(watcher)
+    }
+
+    /// Connects to the first provider available, returns true if one is
+    ///
+    /// Only the first attempt warns, retries fail quietly until the compositor is up.
+    fn connect(&mut self, first: bool) -> bool {
+        self.retry = None;
+        for &provider in &self.providers {
+            match Source::connect(provider, self.socket.as_deref()) {
+                Ok((mut source, query)) => {
+                    let event = EpollEvent::new(EpollFlags::EPOLLIN, 0);
+                    let added = self
+                        .epoll
+                        .add(source.stream().as_fd(), event)
+                        .and_then(|()| {
+                            query
+                                .as_ref()
+                                .map_or(Ok(()), |query| self.epoll.add(&query.stream, event))
+                        });
+                    if let Err(e) = added {
+                        warn!("Failed to wait on the focused app: {}", e);
+                        continue;
+                    }
+                    info!("Following the focused app through {:?}", provider);
+                    self.source = Some(source);
+                    self.query = query;
+                    return true;
+                }
+                Err(e) if self.quiet || !first => {
+                    debug!("No {:?} to follow the focused app: {}", provider, e)
+                }
+                Err(e) => warn!(
+                    "Failed to follow the focused app through {:?}: {}",
+                    provider, e
+                ),
+            }
+        }
+
+        if !self.providers.is_empty() {
+            self.retry = Some(Instant::now() + RETRY);
+        }
+        false
+    }
+
+    /// Drops the provider, to reconnect later
+    fn disconnect(&mut self) {
+        self.source = None;
+        self.query = None;
+        self.buf.clear();
+        self.retry = Some(Instant::now() + RETRY);
+    }
+
+    /// When to try connecting again, if there's no provider
+    pub fn deadline(&self) -> Option<Instant> {
+        self.retry
+    }
+
+    /// Reads pending focus changes, returns the last one, with None for no focused window
+    pub fn changes(&mut self) -> Option<Option<String>> {
+        if self.retry.is_some_and(|retry| retry <= Instant::now()) {
+            self.connect(false);
+        }
+
+        // A finished lookup is the focus until a newer change below
+        let mut focused = self.read_query();
+
+        let Some(source) = self.source.as_mut() else {
+            return focused;
+        };
+        let mut chunk = [0u8; 4096];
+        loop {
+            match source.stream().read(&mut chunk) {
+                Ok(0) => {
+                    warn!("The focused app provider went away, reconnecting");
+                    self.disconnect();
+                    return focused;
+                }
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("Failed to read the focused app, reconnecting: {}", e);
+                    self.disconnect();
+                    return focused;
+                }
+            }
+        }
+
+        match source {
+            Source::I3(stream) => {
+                while let Some((kind, payload)) = take_i3_message(&mut self.buf) {
+                    if kind & I3_EVENT != 0 {
+                        // Which window has focus is easiest to tell from the whole tree
+                        let _ = stream.write_all(&i3_message(I3_GET_TREE, ""));
+                    } else if kind == I3_GET_TREE {
+                        let tree: Value = serde_json::from_slice(&payload).unwrap_or_default();
+                        focused = Some(i3_focused(&tree).flatten());
+                    }
+                }
+            }
+            Source::Hyprland(_) => {
+                for line in take_lines(&mut self.buf) {
+                    if let Some(window) = line.strip_prefix("activewindow>>") {
+                        // Newer than what the initial lookup would find
+                        self.query = None;
+                        let class = window.split(',').next().unwrap_or_default();
+                        focused = Some((!class.is_empty()).then(|| class.to_string()));
+                    }
+                }
+            }
+            Source::X11 { .. } => {
+                let mut window = None;
+                for line in take_lines(&mut self.buf) {
+                    // _NET_ACTIVE_WINDOW(WINDOW): window id # 0x2a00003
+                    if let Some(id) = line.split("# ").nth(1) {
+                        window = Some(id.split(',').next().unwrap_or_default().trim().to_string());
+                    }
+                }
+                match window.as_deref() {
+                    Some("0x0") => {
+                        self.query = None;
+                        focused = Some(None);
+                    }
+                    Some(id) => self.start_query(id),
+                    None => {}
+                }
+            }
+        }
+        focused
+    }
+
+    /// Starts looking up a window's class, replacing any lookup still running
+    fn start_query(&mut self, id: &str) {
+        self.query = None;
+        let query = UnixStream::pair().and_then(|(stdout, child_stdout)| {
+            // WM_CLASS(STRING) = "Navigator", "firefox"
+            let xprop = xprop()
+                .args(["-id", id, "WM_CLASS"])
+                .stdout(Stdio::from(OwnedFd::from(child_stdout)))
+                .stderr(Stdio::null())
+                .spawn()?;
+            let query = Query {
+                stream: stdout,
+                output: Vec::new(),
+                xprop: Some(xprop),
+            };
+            query.stream.set_nonblocking(true)?;
+            self.epoll
+                .add(&query.stream, EpollEvent::new(EpollFlags::EPOLLIN, 0))?;
+            Ok(query)
+        });
+
+        match query {
+            Ok(query) => self.query = Some(query),
+            Err(e) => warn!("Failed to look up the focused window: {}", e),
+        }
+    }
+
+    /// Reads the running lookup, returns the app once it's done
+    fn read_query(&mut self) -> Option<Option<String>> {
+        let query = self.query.as_mut()?;
+        let mut chunk = [0u8; 1024];
+        loop {
+            match query.stream.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => query.output.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+
+        let query = self.query.take()?;
+        if query.xprop.is_some() {
+            return Some(x11_class(&String::from_utf8_lossy(&query.output)));
+        }
+        let window: Value = serde_json::from_slice(&query.output).unwrap_or_default();
+        let class = window["class"].as_str().unwrap_or_default();
+        Some((!class.is_empty()).then(|| class.to_string()))
+    }
+}
+
+impl AsFd for ContextWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll.0.as_fd()
+    }
+}
+
+impl Source {
+    /// Connects to a provider at `socket`, or the one found from the environment, also returning
+    /// a lookup of the window focused now if the provider only tells about changes
+    fn connect(
+        provider: Provider,
+        socket: Option<&Path>,
+    ) -> Result<(Self, Option<Query>), Box<dyn Error>> {
+        match provider {
+            Provider::Sway => {
+                let path = match socket {
+                    Some(path) => path.to_path_buf(),
+                    None => i3_socket()?,
+                };
+                let mut stream = UnixStream::connect(path)?;
+                stream.write_all(&i3_message(I3_SUBSCRIBE, r#"["window","workspace"]"#))?;
+                stream.write_all(&i3_message(I3_GET_TREE, ""))?;
+                stream.set_nonblocking(true)?;
+                Ok((Source::I3(stream), None))
+            }
+            Provider::Hyprland => {
+                let dir = match socket {
+                    Some(dir) => dir.to_path_buf(),
+                    None => hyprland_dir()?,
+                };
+                let stream = UnixStream::connect(dir.join(".socket2.sock"))?;
+                stream.set_nonblocking(true)?;
+
+                // Events only tell about changes, so start from the window focused now
+                let mut request = UnixStream::connect(dir.join(".socket.sock"))?;
+                request.write_all(b"j/activewindow")?;
+                request.set_nonblocking(true)?;
+                let query = Query {
+                    stream: request,
+                    output: Vec::new(),
+                    xprop: None,
+                };
+                Ok((Source::Hyprland(stream), Some(query)))
+            }
+            Provider::X11 => {
+                var("DISPLAY").map_err(|_| "DISPLAY isn't set")?;
+                let (stdout, child_stdout) = UnixStream::pair()?;
+                let xprop = xprop()
+                    .args(["-root", "-spy", "_NET_ACTIVE_WINDOW"])
+                    .stdout(Stdio::from(OwnedFd::from(child_stdout)))
+                    .stderr(Stdio::null())
+                    .spawn()?;
+                stdout.set_nonblocking(true)?;
+                Ok((Source::X11 { xprop, stdout }, None))
+            }
+            Provider::Auto | Provider::None => Err("not a provider".into()),
+        }
+    }
+}
+
+/// The socket of the running sway or i3
+fn i3_socket() -> Result<PathBuf, Box<dyn Error>> {
+    if let Ok(path) = var("SWAYSOCK").or_else(|_| var("I3SOCK")) {
+        return Ok(path.into());
+    }
+    let dir = runtime_dir()?;
+    let path = find_socket(&dir, |name| name.starts_with("sway-ipc."))
+        .or_else(|| find_socket(&dir.join("i3"), |name| name.starts_with("ipc-socket.")))
+        .ok_or("no sway or i3 socket")?;
+    Ok(path)
+}
+
+/// The directory of the running Hyprland instance's sockets
+fn hyprland_dir() -> Result<PathBuf, Box<dyn Error>> {
+    let dir = runtime_dir()?.join("hypr");
+    Ok(match var("HYPRLAND_INSTANCE_SIGNATURE") {
+        Ok(signature) => dir.join(signature),
+        Err(_) => read_dir(&dir)?
+            .flatten()
+            .map(|entry| entry.path())
+            .find(|path| path.join(".socket2.sock").exists())
+            .ok_or("no Hyprland instance")?,
+    })
+}
+
+/// The runtime dir of the session user, where compositors put their sockets
+fn runtime_dir() -> Result<PathBuf, Box<dyn Error>> {
+    if !geteuid().is_root() {
+        if let Ok(dir) = var("XDG_RUNTIME_DIR") {
+            return Ok(dir.into());
+        }
+    }
+    let uid = session_uid().ok_or("no session user")?;
+    Ok(format!("/run/user/{}", uid).into())
+}
+
+fn find_socket(dir: &PathBuf, matches: impl Fn(&str) -> bool) -> Option<PathBuf> {
+    read_dir(dir)
+        .ok()?
+        .flatten()
+        .find(|entry| matches(&entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+}
+
+fn i3_message(kind: u32, payload: &str) -> Vec<u8> {
+    let mut message = I3_MAGIC.to_vec();
+    message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&kind.to_ne_bytes());
+    message.extend_from_slice(payload.as_bytes());
+    message
+}
+
+/// Takes a complete message off the front of `buf`, as its type & payload
+fn take_i3_message(buf: &mut Vec<u8>) -> Option<(u32, Vec<u8>)> {
+    let header = I3_MAGIC.len() + 8;
+    let field = |at: usize| u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap());
+    if buf.len() < header {
+        return None;
+    }
+    let len = field(I3_MAGIC.len()) as usize;
+    if buf.len() < header + len {
+        return None;
+    }
+
+    let kind = field(I3_MAGIC.len() + 4);
+    let payload = buf[header..header + len].to_vec();
+    buf.drain(..header + len);
+    Some((kind, payload))
+}
+
+/// Finds the focused node, its app ID or X11 class if it's a window
+fn i3_focused(node: &Value) -> Option<Option<String>> {
+    if node["focused"] == true {
+        let app = node["app_id"]
+            .as_str()
+            .or(node["window_properties"]["class"].as_str());
+        return Some(app.map(String::from));
+    }
+
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|children| node[children].as_array())
+        .flatten()
+        .find_map(i3_focused)
+}
+
+/// Takes the complete lines off the front of `buf`
+fn take_lines(buf: &mut Vec<u8>) -> Vec<String> {
+    let Some(end) = buf.iter().rposition(|&b| b == b'\n') else {
+        return Vec::new();
+    };
+    let lines: Vec<u8> = buf.drain(..=end).collect();
+    String::from_utf8_lossy(&lines)
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+/// xprop, run as the session user when rk runs as root
+fn xprop() -> Command {
+    let mut command = Command::new("xprop");
+    if geteuid().is_root() {
+        let user = session_uid().and_then(|uid| User::from_uid(Uid::from_raw(uid)).ok().flatten());
+        if let Some(user) = user {
+            command.uid(user.uid.as_raw()).gid(user.gid.as_raw());
+        }
+    }
+    command
+}
+
+/// The class of an X11 window, the second string of its WM_CLASS in xprop's output
+fn x11_class(output: &str) -> Option<String> {
+    output
+        .split('"')
+        .skip(1)
+        .step_by(2)
+        .nth(1)
+        .map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs::{create_dir_all, remove_dir_all, remove_file};
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn i3_messages_are_framed() {
+        let mut buf = i3_message(I3_GET_TREE, "{}");
+        assert_eq!(buf[..6], *b"i3-ipc");
+        assert_eq!(buf[6..10], 2u32.to_ne_bytes());
+        assert_eq!(buf[10..14], I3_GET_TREE.to_ne_bytes());
+
+        // Messages arrive in pieces & back to back
+        buf.extend_from_slice(&i3_message(I3_EVENT | 3, "[1]"));
+        let rest = buf.split_off(buf.len() - 2);
+        assert_eq!(
+            take_i3_message(&mut buf),
+            Some((I3_GET_TREE, b"{}".to_vec()))
+        );
+        assert_eq!(take_i3_message(&mut buf), None);
+        buf.extend_from_slice(&rest);
+        assert_eq!(
+            take_i3_message(&mut buf),
+            Some((I3_EVENT | 3, b"[1]".to_vec()))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn i3_focus_is_found_in_the_tree() {
+        let window = |focused, app_id: Option<&str>, class: Option<&str>| {
+            json!({
+                "focused": focused,
+                "app_id": app_id,
+                "window_properties": { "class": class },
+                "nodes": [],
+                "floating_nodes": [],
+            })
+        };
+        let tree = |nodes, floating| {
+            json!({
+                "focused": false,
+                "nodes": [{ "focused": false, "nodes": nodes, "floating_nodes": floating }],
+            })
+        };
+
+        let wayland = tree(
+            vec![
+                window(false, Some("foot"), None),
+                window(true, Some("firefox"), None),
+            ],
+            vec![],
+        );
+        assert_eq!(i3_focused(&wayland), Some(Some("firefox".into())));
+
+        let xwayland = tree(vec![], vec![window(true, None, Some("Gimp"))]);
+        assert_eq!(i3_focused(&xwayland), Some(Some("Gimp".into())));
+
+        // A focused workspace has no app
+        let workspace = json!({ "focused": false, "nodes": [{ "focused": true, "nodes": [] }] });
+        assert_eq!(i3_focused(&workspace), Some(None));
+        assert_eq!(
+            i3_focused(&tree(vec![window(false, Some("foot"), None)], vec![])),
+            None
+        );
+    }
+
+    #[test]
+    fn lines_are_taken_once_complete() {
+        let mut buf = b"activewindow>>foot,~\nactivewindow>>fire".to_vec();
+        assert_eq!(take_lines(&mut buf), ["activewindow>>foot,~"]);
+        assert_eq!(buf, b"activewindow>>fire");
+        assert!(take_lines(&mut buf).is_empty());
+    }
+
+    #[test]
+    fn x11_class_is_the_second_wm_class_string() {
+        assert_eq!(
+            x11_class("WM_CLASS(STRING) = \"Navigator\", \"firefox\"\n").as_deref(),
+            Some("firefox")
+        );
+        assert_eq!(x11_class("WM_CLASS:  not found.\n"), None);
+    }
+
+    #[test]
+    fn reconnects_once_the_provider_is_up() {
+        let path = std::env::temp_dir().join(format!("rk-sway-{}.sock", std::process::id()));
+        let config = ContextConfig {
+            provider: Provider::Sway,
+        };
+        let _ = remove_file(&path);
+        let mut watcher = ContextWatcher::with_socket(&config, Some(path.clone())).unwrap();
+        assert!(watcher.deadline().is_some());
+
+        let listener = UnixListener::bind(&path).unwrap();
+        watcher.retry = Some(Instant::now());
+        assert_eq!(watcher.changes(), None);
+        assert_eq!(watcher.deadline(), None);
+
+        let (mut sway, _) = listener.accept().unwrap();
+        let tree = json!({ "focused": true, "app_id": "firefox" }).to_string();
+        sway.write_all(&i3_message(I3_GET_TREE, &tree)).unwrap();
+        assert_eq!(watcher.changes(), Some(Some("firefox".into())));
+
+        // And again once it goes away
+        drop(sway);
+        assert_eq!(watcher.changes(), None);
+        assert!(watcher.deadline().is_some());
+        let _ = remove_file(&path);
+    }
+
+    #[test]
+    fn hyprland_starts_from_the_window_focused_now() {
+        let dir = std::env::temp_dir().join(format!("rk-hypr-{}", std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let events = UnixListener::bind(dir.join(".socket2.sock")).unwrap();
+        let requests = UnixListener::bind(dir.join(".socket.sock")).unwrap();
+        let config = ContextConfig {
+            provider: Provider::Hyprland,
+        };
+        let mut watcher = ContextWatcher::with_socket(&config, Some(dir.clone())).unwrap();
+
+        // The lookup doesn't hold anything up while Hyprland hasn't answered yet
+        let (_events, _) = events.accept().unwrap();
+        let (mut request, _) = requests.accept().unwrap();
+        assert_eq!(watcher.changes(), None);
+
+        let mut line = [0u8; 14];
+        request.read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"j/activewindow");
+        request.write_all(br#"{"class":"kitty"}"#).unwrap();
+        drop(request);
+        assert_eq!(watcher.changes(), Some(Some("kitty".into())));
+        let _ = remove_dir_all(&dir);
+    }
+}
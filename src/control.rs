@@ -0,0 +1,561 @@
+use serde::{Deserialize, Serialize};
+use std::env::var;
+use std::error::Error;
+use std::fs::{create_dir_all, remove_file, set_permissions, Permissions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::fs::{chown, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
+use nix::unistd::Group;
+
+use crate::device::Keyboard;
+use crate::socket::socket_paths;
+use crate::{led_name, Config, SharedState};
+
+/// Clients get this long to send their request, & to read the reply, before they're dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Default)]
+pub struct ControlConfig {
+    /// Group allowed to use the control socket besides its owner
+    group: Option<String>,
+}
+
+pub enum LayerOp {
+    On,
+    Off,
+    Toggle,
+}
+
+pub enum Request {
+    Status,
+    Enable,
+    Disable,
+    Toggle,
+    Layer(LayerOp, String),
+    Profile(String, Option<String>),
+    Reload,
+    Devices,
+    Rules(Option<String>),
+    /// The focused app, for compositors rk can't follow itself
+    SetContext(Option<String>),
+    Watch,
+}
+
+impl Request {
+    /// Parses a request line, arguments are separated by tabs, or spaces if there are none
+    fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let args: Vec<&str> = if line.contains('\t') {
+            line.split('\t').collect()
+        } else {
+            line.split_whitespace().collect()
+        };
+
+        Ok(match args.as_slice() {
+            ["status"] => Request::Status,
+            ["enable"] => Request::Enable,
+            ["disable"] => Request::Disable,
+            ["toggle"] => Request::Toggle,
+            ["layer", op, name] => {
+                let op = match *op {
+                    "on" => LayerOp::On,
+                    "off" => LayerOp::Off,
+                    "toggle" => LayerOp::Toggle,
+                    other => return Err(format!("Unknown layer operation: {}", other)),
+                };
+                Request::Layer(op, name.to_string())
+            }
+            ["profile", device, profile] => {
+                let profile = (*profile != "auto").then(|| profile.to_string());
+                Request::Profile(device.to_string(), profile)
+            }
+            ["reload"] => Request::Reload,
+            ["devices"] => Request::Devices,
+            ["rules"] => Request::Rules(None),
+            ["rules", device] => Request::Rules(Some(device.to_string())),
+            ["set-context"] => Request::SetContext(None),
+            ["set-context", app] => Request::SetContext(Some(app.to_string())),
+            ["watch"] => Request::Watch,
+            _ => return Err(format!("Unknown command: {}", args.join(" "))),
+        })
+    }
+}
+
+/// A connection whose request hasn't fully arrived yet, or whose reply hasn't been sent yet
+struct Client {
+    stream: UnixStream,
+    buf: Vec<u8>,
+    since: Instant,
+}
+
+/// Unix socket accepting one request per connection from rkctl
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    /// Wakes on new connections, request data & clients ready for the rest of their reply, it's
+    /// what the main loop waits on
+    epoll: Epoll,
+    clients: Vec<Client>,
+    /// Replies too long to send at once, written as the clients read them
+    replies: Vec<Client>,
+    /// Connections following the status stream
+    watchers: Vec<UnixStream>,
+    /// The last status line sent, to send only changes
+    status: String,
+}
+
+impl ControlServer {
+    /// Listens on the first socket that can be created, None if none can
+    ///
+    /// rk still remaps without it, only another rk already listening is an error.
+    pub fn new(config: &ControlConfig) -> Result<Option<Self>, Box<dyn Error>> {
+        for path in socket_paths() {
+            // A socket nobody answers on is left over from an rk that didn't exit cleanly
+            if path.exists() && UnixStream::connect(&path).is_ok() {
+                return Err(format!("rk is already running, {} is in use", path.display()).into());
+            }
+
+            match Self::bind(path.clone(), config) {
+                Ok(server) => {
+                    info!("Listening on {}", path.display());
+                    return Ok(Some(server));
+                }
+                Err(e) => warn!("Can't create control socket {}: {}", path.display(), e),
+            }
+        }
+
+        warn!("Running without a control socket, rkctl won't work");
+        Ok(None)
+    }
+
+    fn bind(path: PathBuf, config: &ControlConfig) -> Result<Self, Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        if path.exists() {
+            remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        epoll.add(&listener, EpollEvent::new(EpollFlags::EPOLLIN, 0))?;
+
+        // When started through sudo, the user who ran it owns the socket
+        let uid = var("SUDO_UID").ok().and_then(|id| id.parse().ok());
+        let gid = match &config.group {
+            Some(name) => Some(
+                Group::from_name(name)?
+                    .ok_or_else(|| format!("Unknown control group: {}", name))?
+                    .gid
+                    .as_raw(),
+            ),
+            None => var("SUDO_GID").ok().and_then(|id| id.parse().ok()),
+        };
+        chown(&path, uid, gid)?;
+        let mode = if config.group.is_some() { 0o660 } else { 0o600 };
+        set_permissions(&path, Permissions::from_mode(mode))?;
+
+        Ok(Self {
+            listener,
+            path,
+            epoll,
+            clients: Vec::new(),
+            replies: Vec::new(),
+            watchers: Vec::new(),
+            status: String::new(),
+        })
+    }
+
+    /// Accepts pending connections and returns the requests that have fully arrived, without
+    /// waiting for the rest
+    pub fn requests(&mut self) -> Vec<(UnixStream, Result<Request, String>)> {
+        let replies = std::mem::take(&mut self.replies);
+        for mut reply in replies {
+            if Self::send(&mut reply) || reply.since.elapsed() >= REQUEST_TIMEOUT {
+                let _ = self.epoll.delete(&reply.stream);
+            } else {
+                self.replies.push(reply);
+            }
+        }
+
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let added = stream.set_nonblocking(true).and_then(|()| {
+                        Ok(self
+                            .epoll
+                            .add(&stream, EpollEvent::new(EpollFlags::EPOLLIN, 0))?)
+                    });
+                    match added {
+                        Ok(()) => self.clients.push(Client {
+                            stream,
+                            buf: Vec::new(),
+                            since: Instant::now(),
+                        }),
+                        Err(e) => warn!("Failed to accept control connection: {}", e),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to accept control connection: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let mut requests = Vec::new();
+        let mut pending = Vec::new();
+        for mut client in self.clients.drain(..) {
+            let mut chunk = [0u8; 512];
+            let done = loop {
+                match client.stream.read(&mut chunk) {
+                    // Closed, with or without a request, like another rk checking if we're alive
+                    Ok(0) => break true,
+                    Ok(n) => client.buf.extend_from_slice(&chunk[..n]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break false,
+                    Err(e) => {
+                        warn!("Failed to read control request: {}", e);
+                        client.buf.clear();
+                        break true;
+                    }
+                }
+            };
+
+            let line = client
+                .buf
+                .iter()
+                .position(|&b| b == b'\n')
+                .map(|end| String::from_utf8_lossy(&client.buf[..end]).into_owned())
+                .or_else(|| {
+                    (done && !client.buf.is_empty())
+                        .then(|| String::from_utf8_lossy(&client.buf).into_owned())
+                });
+
+            if line.is_none() && !done && client.since.elapsed() < REQUEST_TIMEOUT {
+                pending.push(client);
+                continue;
+            }
+
+            let _ = self.epoll.delete(&client.stream);
+            if let Some(line) = line {
+                requests.push((client.stream, Request::parse(&line)));
+            }
+        }
+        self.clients = pending;
+
+        requests
+    }
+
+    /// When the oldest pending request or reply times out
+    pub fn deadline(&self) -> Option<Instant> {
+        self.clients
+            .iter()
+            .chain(&self.replies)
+            .map(|client| client.since + REQUEST_TIMEOUT)
+            .min()
+    }
+
+    /// Sends the result of a request, errors prefixed with "error: "
+    ///
+    /// What the client doesn't read right away is sent as it does, never blocking the main loop.
+    pub fn reply(&mut self, stream: UnixStream, result: Result<String, String>) {
+        let text = match result {
+            Ok(text) => text,
+            Err(e) => format!("error: {}\n", e),
+        };
+        let mut reply = Client {
+            stream,
+            buf: text.into_bytes(),
+            since: Instant::now(),
+        };
+
+        if !Self::send(&mut reply)
+            && self
+                .epoll
+                .add(&reply.stream, EpollEvent::new(EpollFlags::EPOLLOUT, 0))
+                .is_ok()
+        {
+            self.replies.push(reply);
+        }
+    }
+
+    /// Writes as much of the reply as the client takes, true once it's done with
+    fn send(reply: &mut Client) -> bool {
+        while !reply.buf.is_empty() {
+            match reply.stream.write(&reply.buf) {
+                Ok(n) => drop(reply.buf.drain(..n)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+                Err(e) => {
+                    warn!("Failed to send control reply: {}", e);
+                    return true;
+                }
+            }
+        }
+        true
+    }
+
+    /// Adds a connection to the status stream, starting with the current status
+    pub fn subscribe(&mut self, mut stream: UnixStream) {
+        if stream.set_nonblocking(true).is_ok() && stream.write_all(self.status.as_bytes()).is_ok()
+        {
+            self.watchers.push(stream);
+        }
+    }
+
+    /// Sends `status` to every watcher if it changed, dropping watchers that can't keep up
+    pub fn publish(&mut self, status: &Status) {
+        let Ok(json) = serde_json::to_string(status) else {
+            return;
+        };
+        let line = json + "\n";
+        if line == self.status {
+            return;
+        }
+
+        self.watchers
+            .retain_mut(|stream| stream.write_all(line.as_bytes()).is_ok());
+        self.status = line;
+    }
+}
+
+/// A status stream line, for status bars
+#[derive(Serialize)]
+pub struct Status {
+    pub enabled: bool,
+    /// The top active layer
+    pub layer: Option<String>,
+    pub layers: Vec<String>,
+    pub profile: Option<String>,
+    pub device: Option<String>,
+    pub leds: Vec<String>,
+    /// Flags that are on
+    pub flags: Vec<String>,
+    /// The focused app
+    pub app: Option<String>,
+}
+
+/// The shared state, with the layers & LEDs of the keyboard at `last_used` or the first keyboard
+pub fn status(keyboards: &[Keyboard], shared: &SharedState, last_used: Option<&Path>) -> Status {
+    let kb = keyboards
+        .iter()
+        .find(|kb| Some(kb.path.as_path()) == last_used)
+        .or(keyboards.first());
+    let flags = shared
+        .flags
+        .iter()
+        .filter(|(_, &on)| on)
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    let Some(kb) = kb else {
+        return Status {
+            enabled: shared.enabled,
+            layer: None,
+            layers: Vec::new(),
+            profile: None,
+            device: None,
+            leds: Vec::new(),
+            flags,
+            app: shared.app.clone(),
+        };
+    };
+
+    let remapper = &kb.remapper;
+    let layers: Vec<String> = remapper
+        .active_layers
+        .iter()
+        .rev()
+        .map(|&l| remapper.keymap.layers[l].name.clone())
+        .collect();
+
+    Status {
+        enabled: shared.enabled,
+        layer: remapper.top_layer().map(String::from),
+        layers,
+        profile: kb.profile.clone(),
+        device: Some(kb.name().to_string()),
+        leds: remapper.leds.iter().map(|&l| led_name(l)).collect(),
+        flags,
+        app: shared.app.clone(),
+    }
+}
+
+impl AsFd for ControlServer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll.0.as_fd()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+fn find_keyboards<'a>(
+    keyboards: &'a mut [Keyboard],
+    device: &str,
+) -> Result<Vec<&'a mut Keyboard>, String> {
+    let found: Vec<&mut Keyboard> = keyboards
+        .iter_mut()
+        .filter(|kb| kb.name() == device || kb.path == Path::new(device))
+        .collect();
+
+    if found.is_empty() {
+        Err(format!("Unknown device: {}", device))
+    } else {
+        Ok(found)
+    }
+}
+
+/// Runs a request against the keyboards & shared state, except `Reload` which the main loop
+/// handles
+pub fn handle(
+    request: Request,
+    keyboards: &mut [Keyboard],
+    shared: &mut SharedState,
+    config: &Config,
+) -> Result<String, String> {
+    let mut out = String::new();
+
+    match request {
+        Request::Status => {
+            out += &format!("enabled: {}\n", shared.enabled);
+            let status = status(keyboards, shared, None);
+            out += &format!("flags: {}\n", status.flags.join(", "));
+            out += &format!("app: {}\n", status.app.as_deref().unwrap_or("none"));
+            for kb in keyboards.iter() {
+                let remapper = &kb.remapper;
+                let layers: Vec<String> = remapper
+                    .keymap
+                    .layers
+                    .iter()
+                    .enumerate()
+                    .map(|(i, layer)| match remapper.active_layers.contains(&i) {
+                        true => format!("{} (active)", layer.name),
+                        false => layer.name.clone(),
+                    })
+                    .collect();
+                let leds: Vec<String> = remapper.leds.iter().map(|&l| led_name(l)).collect();
+
+                out += &format!("{} ({})\n", kb.name(), kb.path.display());
+                out += &format!(
+                    "  profile: {}\n",
+                    kb.profile.as_deref().unwrap_or("default")
+                );
+                out += &format!("  layers: {}\n", layers.join(", "));
+                out += &format!("  leds: {}\n", leds.join(", "));
+            }
+        }
+        Request::Enable | Request::Disable | Request::Toggle => {
+            shared.enabled = match request {
+                Request::Enable => true,
+                Request::Disable => false,
+                _ => !shared.enabled,
+            };
+        }
+        Request::Layer(op, name) => {
+            let mut found = false;
+            for kb in keyboards.iter_mut() {
+                let remapper = &mut kb.remapper;
+                let Some(layer) = remapper.keymap.layer_index(&name) else {
+                    continue;
+                };
+                found = true;
+
+                match op {
+                    LayerOp::On => remapper.activate_layer(layer),
+                    LayerOp::Off => remapper.deactivate_layer(layer),
+                    LayerOp::Toggle if remapper.active_layers.contains(&layer) => {
+                        remapper.deactivate_layer(layer)
+                    }
+                    LayerOp::Toggle => remapper.activate_layer(layer),
+                }
+            }
+
+            if !found {
+                return Err(format!("Unknown layer: {}", name));
+            }
+        }
+        Request::Profile(device, profile) => {
+            for kb in find_keyboards(keyboards, &device)? {
+                kb.set_profile(profile.as_deref(), config)
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        Request::Devices => {
+            for kb in keyboards.iter() {
+                out += &format!(
+                    "{}: {} ({})\n",
+                    kb.path.display(),
+                    kb.name(),
+                    kb.profile.as_deref().unwrap_or("default")
+                );
+            }
+        }
+        Request::Rules(device) => {
+            let keyboards = match device {
+                Some(device) => find_keyboards(keyboards, &device)?,
+                None => keyboards.iter_mut().collect(),
+            };
+
+            for kb in keyboards {
+                let keymap = &kb.remapper.keymap;
+                out += &format!("{} ({})\n", kb.name(), kb.path.display());
+                for rule in keymap
+                    .rules
+                    .iter()
+                    .chain(keymap.layers.iter().flat_map(|l| &l.rules))
+                {
+                    out += &format!("  {}\n", rule.source);
+                }
+            }
+        }
+        Request::SetContext(app) => shared.app = app.filter(|app| !app.is_empty()),
+        Request::Reload | Request::Watch => {
+            return Err("Reload & watch are handled by the main loop".into())
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn replies_wait_for_clients_that_dont_read() {
+        let path = std::env::temp_dir().join(format!("rk-control-{}.sock", process::id()));
+        let mut server = ControlServer::bind(path, &ControlConfig::default()).unwrap();
+        let mut client = UnixStream::connect(&server.path).unwrap();
+        client.write_all(b"status\n").unwrap();
+
+        let (stream, request) = server.requests().pop().unwrap();
+        assert!(matches!(request, Ok(Request::Status)));
+        assert_eq!(server.deadline(), None);
+
+        // More than the socket buffers hold, so the rest waits for the client
+        let text = "x".repeat(1 << 22);
+        server.reply(stream, Ok(text.clone()));
+        assert!(server.deadline().is_some());
+
+        let mut received = Vec::new();
+        let mut chunk = [0u8; 1 << 16];
+        while received.len() < text.len() {
+            let n = client.read(&mut chunk).unwrap();
+            received.extend_from_slice(&chunk[..n]);
+            server.requests();
+        }
+        assert_eq!(received, text.as_bytes());
+        assert_eq!(server.deadline(), None);
+    }
+}
@@ -0,0 +1,46 @@
+use std::env::var_os;
+use std::error::Error;
+use std::fs::{remove_file, write};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::PathBuf;
+use std::process;
+
+use log::warn;
+
+/// Sends a state like `READY=1` to systemd, if it started us as a `Type=notify` service
+pub fn notify(state: &str) {
+    let Some(socket) = var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    // Sockets starting with @ are in the abstract namespace
+    let addr = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&socket),
+    };
+    let sent = UnixDatagram::unbound()
+        .and_then(|sock| addr.and_then(|addr| sock.send_to_addr(state.as_bytes(), &addr)));
+
+    if let Err(e) = sent {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Holds our PID in a file for as long as it's alive
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> Result<Self, Box<dyn Error>> {
+        write(&path, format!("{}\n", process::id()))
+            .map_err(|e| format!("Failed to write PID file {}: {}", path.display(), e))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
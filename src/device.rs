@@ -0,0 +1,430 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::{canonicalize, read_dir};
+use std::path::{Path, PathBuf};
+
+use evdev::{Device, EventSummary, EventType, InputEvent, KeyCode, LedCode};
+use log::{info, warn};
+
+use crate::{Config, KeyRemapper, Keymap, Profile, VIRTUAL_DEVICE_NAME, VIRTUAL_POINTER_NAME};
+
+/// `[device.*]` profiles keyed by device name, `usb` vendor:product ID or `path`
+#[derive(Deserialize, Default)]
+pub struct DeviceConfig {
+    #[serde(default)]
+    pub usb: HashMap<String, Profile>,
+    #[serde(default)]
+    pub path: HashMap<String, Profile>,
+    #[serde(flatten)]
+    pub name: HashMap<String, Profile>,
+}
+
+impl DeviceConfig {
+    /// Finds the profile for a device, preferring its path over its ID over its name
+    fn profile(&self, dev: &Device, path: &Path) -> Option<(String, &Profile)> {
+        let real_path = canonicalize(path).ok();
+        let id = dev.input_id();
+        let usb_id = format!("{:04x}:{:04x}", id.vendor(), id.product());
+
+        let by_path = self
+            .path
+            .iter()
+            .find(|(p, _)| real_path.is_some() && canonicalize(p).ok() == real_path)
+            .map(|(p, profile)| (format!("device.path.\"{}\"", p), profile));
+        let by_usb = self
+            .usb
+            .iter()
+            .find(|(id, _)| id.eq_ignore_ascii_case(&usb_id))
+            .map(|(id, profile)| (format!("device.usb.\"{}\"", id), profile));
+        let by_name = dev
+            .name()
+            .and_then(|name| self.name.get_key_value(name))
+            .map(|(name, profile)| (format!("device.\"{}\"", name), profile));
+
+        by_path.or(by_usb).or(by_name)
+    }
+
+    /// Looks up a profile by its device name, USB ID or path
+    fn find(&self, key: &str) -> Option<(String, &Profile)> {
+        let by_name = self
+            .name
+            .get_key_value(key)
+            .map(|(name, profile)| (format!("device.\"{}\"", name), profile));
+        let by_usb = self
+            .usb
+            .iter()
+            .find(|(id, _)| id.eq_ignore_ascii_case(key))
+            .map(|(id, profile)| (format!("device.usb.\"{}\"", id), profile));
+        let by_path = self
+            .path
+            .get_key_value(key)
+            .map(|(p, profile)| (format!("device.path.\"{}\"", p), profile));
+
+        by_name.or(by_usb).or(by_path)
+    }
+}
+
+/// `[devices]` filters on which devices get grabbed, as globs
+#[derive(Deserialize, Default)]
+pub struct DeviceFilter {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl DeviceFilter {
+    /// Returns true if `dev` should be grabbed, `is_keyboard` being the default guess
+    fn allows(&self, dev: &Device, path: &Path, is_keyboard: bool) -> bool {
+        let names = identifiers(dev, path);
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|p| names.iter().any(|name| glob_match(p, name)))
+        };
+
+        let included = if self.include.is_empty() {
+            is_keyboard
+        } else {
+            matches(&self.include)
+        };
+        included && !matches(&self.exclude)
+    }
+}
+
+/// Everything a device can be matched by: name, phys, uniq, vendor:product & its paths
+fn identifiers(dev: &Device, path: &Path) -> Vec<String> {
+    let id = dev.input_id();
+    let mut names: Vec<String> = [dev.name(), dev.physical_path(), dev.unique_name()]
+        .into_iter()
+        .flatten()
+        .map(String::from)
+        .collect();
+    names.push(format!("{:04x}:{:04x}", id.vendor(), id.product()));
+    names.push(path.display().to_string());
+
+    let real_path = canonicalize(path).ok();
+    for dir in ["/dev/input/by-id", "/dev/input/by-path"] {
+        for link in read_dir(dir).into_iter().flatten().flatten() {
+            if real_path.is_some() && canonicalize(link.path()).ok() == real_path {
+                names.push(link.path().display().to_string());
+            }
+        }
+    }
+
+    names
+}
+
+/// Matches `text` against a glob with `*` & `?` wildcards, ignoring case
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` & the text position it's currently matched up to
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// True if some text matches both globs, e.g. `fire*` & `*fox`
+pub fn globs_overlap(a: &str, b: &str) -> bool {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    // Positions in both globs after matching the same text so far
+    let mut seen = HashSet::new();
+    let mut stack = vec![(0, 0)];
+
+    while let Some((i, j)) = stack.pop() {
+        if !seen.insert((i, j)) {
+            continue;
+        }
+        if i == a.len() && j == b.len() {
+            return true;
+        }
+
+        // A `*` can match nothing
+        if a.get(i) == Some(&'*') {
+            stack.push((i + 1, j));
+        }
+        if b.get(j) == Some(&'*') {
+            stack.push((i, j + 1));
+        }
+
+        // Or both take the next character, a `*` staying put to take more
+        let (Some(&x), Some(&y)) = (a.get(i), b.get(j)) else {
+            continue;
+        };
+        if x == y || ['*', '?'].contains(&x) || ['*', '?'].contains(&y) {
+            let i = if x == '*' { i } else { i + 1 };
+            let j = if y == '*' { j } else { j + 1 };
+            stack.push((i, j));
+        }
+    }
+    false
+}
+
+/// A grabbed keyboard with its own remapper & virtual device
+pub struct Keyboard {
+    pub device: Device,
+    pub path: PathBuf,
+    pub profile: Option<String>,
+    /// Profile chosen at runtime with `rkctl profile`, "default" for the top-level mappings
+    pub profile_override: Option<String>,
+    pub remapper: KeyRemapper,
+    /// The indicator LED & the state last written to it, kept out of `remapper.leds`
+    pub indicator: Option<(LedCode, bool)>,
+    /// LEDs set by rk whose change events haven't come back yet
+    leds_written: HashMap<LedCode, bool>,
+}
+
+impl Keyboard {
+    /// Grabs `device` and creates its virtual device
+    pub fn new(mut device: Device, path: PathBuf, config: &Config) -> Result<Self, Box<dyn Error>> {
+        let (profile, keymap) = Self::keymap(&device, &path, None, config)?;
+        let remapper = KeyRemapper::new(&device, keymap)?;
+        device.set_nonblocking(true)?;
+        device.grab()?;
+
+        let kb = Self {
+            device,
+            path,
+            profile,
+            profile_override: None,
+            remapper,
+            indicator: None,
+            leds_written: HashMap::new(),
+        };
+        let keymap = &kb.remapper.keymap;
+        info!(
+            "{}: {} mapping rules, {} layers ({})",
+            kb.name(),
+            keymap.rules.len(),
+            keymap.layers.len(),
+            kb.profile.as_deref().unwrap_or("default profile")
+        );
+        Ok(kb)
+    }
+
+    pub fn name(&self) -> &str {
+        self.device.name().unwrap_or("Unknown")
+    }
+
+    /// Passes an event to the remapper, taking LED changes as the real lock state
+    pub fn process_event(&mut self, event: &InputEvent) -> Result<(), Box<dyn Error>> {
+        let EventSummary::Led(_, led, value) = event.destructure() else {
+            return self.remapper.process_event(event);
+        };
+
+        // Changes rk made itself come back as events too
+        let on = value != 0;
+        if self.leds_written.remove(&led) == Some(on) {
+            return Ok(());
+        }
+
+        self.remapper.set_led(led, on);
+        // Whatever set the LED overwrote the indicator, so it's shown again
+        if self
+            .indicator
+            .is_some_and(|(indicator, _)| indicator == led)
+        {
+            self.indicator = None;
+        }
+        Ok(())
+    }
+
+    /// Turns an LED of the device on or off, if it has one
+    pub fn set_led(&mut self, led: LedCode, on: bool) {
+        if !self
+            .device
+            .supported_leds()
+            .is_some_and(|leds| leds.contains(led))
+        {
+            return;
+        }
+        // Setting an LED to its current state sends no event back
+        if self
+            .device
+            .get_led_state()
+            .is_ok_and(|leds| leds.contains(led) == on)
+        {
+            return;
+        }
+
+        let event = InputEvent::new(EventType::LED.0, led.0, on as i32);
+        match self.device.send_events(&[event]) {
+            Ok(()) => {
+                self.leds_written.insert(led, on);
+            }
+            Err(e) => warn!("Failed to set {:?} on {}: {}", led, self.name(), e),
+        }
+    }
+
+    /// Switches to the profile `name`, "default" for the top-level mappings or None to pick by device
+    pub fn set_profile(
+        &mut self,
+        name: Option<&str>,
+        config: &Config,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(name) = name.filter(|&n| n != "default") {
+            config
+                .device
+                .find(name)
+                .ok_or_else(|| format!("Unknown profile: {}", name))?;
+        }
+
+        let (profile, keymap) = Self::keymap(&self.device, &self.path, name, config)?;
+        self.remapper.prepare_keymap(&keymap)?;
+        self.remapper.set_keymap(keymap);
+        self.profile = profile;
+        self.profile_override = name.map(String::from);
+        Ok(())
+    }
+
+    fn keymap(
+        device: &Device,
+        path: &Path,
+        profile_override: Option<&str>,
+        config: &Config,
+    ) -> Result<(Option<String>, Keymap), Box<dyn Error>> {
+        let profile = match profile_override {
+            Some("default") => None,
+            Some(name) => config.device.find(name).or_else(|| {
+                warn!("Profile {} no longer exists, selecting by device", name);
+                config.device.profile(device, path)
+            }),
+            None => config.device.profile(device, path),
+        };
+        let keymap = Keymap::new(config, profile.as_ref().map(|(_, p)| *p))?;
+        Ok((profile.map(|(label, _)| label), keymap))
+    }
+}
+
+/// Swaps in the keymaps from a new config, only if every keyboard's keymap loads
+pub fn reload(keyboards: &mut [Keyboard], config: &Config) -> Result<(), Box<dyn Error>> {
+    let keymaps = keyboards
+        .iter()
+        .map(|kb| Keyboard::keymap(&kb.device, &kb.path, kb.profile_override.as_deref(), config))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Only swap once nothing else can fail, so every keyboard switches or none does
+    for (kb, (_, keymap)) in keyboards.iter_mut().zip(&keymaps) {
+        kb.remapper.prepare_keymap(keymap)?;
+    }
+    for (kb, (profile, keymap)) in keyboards.iter_mut().zip(keymaps) {
+        kb.profile = profile;
+        kb.remapper.set_keymap(keymap);
+    }
+    Ok(())
+}
+
+/// Opens the device at `path` if it's an event device the filter allows
+pub fn open_keyboard(path: &Path, filter: &DeviceFilter) -> Option<Device> {
+    if !path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with("event"))
+    {
+        return None;
+    }
+
+    let dev = match Device::open(path) {
+        Ok(dev) => dev,
+        Err(e) => {
+            warn!("Failed to open {:?}: {}", path, e);
+            return None;
+        }
+    };
+    // Never grab our own virtual devices
+    if matches!(dev.name(), Some(VIRTUAL_DEVICE_NAME | VIRTUAL_POINTER_NAME)) {
+        return None;
+    }
+
+    let keys = dev.supported_keys()?;
+    let is_keyboard = keys.contains(KeyCode::KEY_A) && !keys.contains(KeyCode::BTN_LEFT);
+
+    if filter.allows(&dev, path, is_keyboard) {
+        info!("Found: {} ({:?})", dev.name().unwrap_or("Unknown"), path);
+        Some(dev)
+    } else {
+        if is_keyboard {
+            info!("Ignored: {} ({:?})", dev.name().unwrap_or("Unknown"), path);
+        }
+        None
+    }
+}
+
+pub fn find_keyboards(filter: &DeviceFilter) -> Result<Vec<(PathBuf, Device)>, Box<dyn Error>> {
+    let mut keyboards = Vec::new();
+
+    for entry in read_dir("/dev/input")? {
+        let path = entry?.path();
+        if let Some(dev) = open_keyboard(&path, filter) {
+            keyboards.push((path, dev));
+        }
+    }
+
+    Ok(keyboards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_literally_ignoring_case() {
+        assert!(glob_match("firefox", "Firefox"));
+        assert!(!glob_match("firefox", "firefox-esr"));
+        assert!(!glob_match("firefox", "fire"));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_match("*term*", "org.wezfurlong.wezterm"));
+        assert!(glob_match("*term*", "xterm"));
+        assert!(!glob_match("foot?", "footclient"));
+        assert!(glob_match("foo?", "foot"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn glob_backtracks_after_a_star() {
+        assert!(glob_match("*ab", "aaab"));
+        assert!(glob_match("a*b*c", "abxbyc"));
+        assert!(!glob_match("a*b*c", "abxbyd"));
+        assert!(glob_match("**a", "ba"));
+    }
+
+    #[test]
+    fn globs_overlap_when_some_text_matches_both() {
+        assert!(globs_overlap("fire*", "Firefox"));
+        assert!(globs_overlap("fire*", "*fox"));
+        assert!(globs_overlap("*term*", "foot?erm"));
+        assert!(globs_overlap("a?c", "*b*"));
+        assert!(!globs_overlap("firefox", "chromium"));
+        assert!(!globs_overlap("fire*", "chrom*"));
+        assert!(!globs_overlap("a?", "abc*d"));
+    }
+}
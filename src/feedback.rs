@@ -0,0 +1,263 @@
+use serde::Deserialize;
+use std::env::var;
+use std::io::{stdout, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use evdev::LedCode;
+use log::warn;
+use nix::unistd::geteuid;
+
+use crate::control::Status;
+use crate::device::Keyboard;
+use crate::notify::Notifier;
+use crate::{parse_led, Config};
+
+/// Ways of letting the user know something changed
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Notification,
+    Bell,
+    Led,
+    Command,
+    None,
+}
+
+/// The backends used for each event
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct FeedbackConfig {
+    pub toggle: Vec<Backend>,
+    pub layer: Vec<Backend>,
+    /// Run through `sh -c` by the command backend
+    pub command: String,
+    /// Flashed by the led backend
+    pub led: String,
+    pub led_flash_ms: u64,
+}
+
+impl Default for FeedbackConfig {
+    fn default() -> Self {
+        Self {
+            toggle: vec![Backend::Notification],
+            layer: Vec::new(),
+            command: String::new(),
+            led: "scrolllock".into(),
+            led_flash_ms: 150,
+        }
+    }
+}
+
+/// What the indicator LED shows
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Indicate {
+    #[default]
+    Enabled,
+    Disabled,
+    Layer,
+}
+
+/// An LED dedicated to showing the remapping state
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct IndicatorConfig {
+    /// None leaves every LED to its lock state
+    pub led: Option<String>,
+    pub show: Indicate,
+    /// Layers lighting the LED with `show = "layer"`, any layer if empty
+    pub layers: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Event {
+    Toggle,
+    /// The top active layer changed
+    Layer,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::Toggle => "toggle",
+            Event::Layer => "layer",
+        }
+    }
+}
+
+/// Sends feedback for events through the configured backends
+#[derive(Default)]
+pub struct Feedback {
+    notifier: Notifier,
+    /// Commands still running, reaped in `tick`
+    commands: Vec<Child>,
+    /// The flashing LED and when to restore it
+    flash: Option<(LedCode, Instant)>,
+}
+
+impl Feedback {
+    pub fn send(
+        &mut self,
+        config: &Config,
+        event: Event,
+        status: &Status,
+        keyboards: &mut [Keyboard],
+    ) {
+        let backends = match event {
+            Event::Toggle => &config.feedback.toggle,
+            Event::Layer => &config.feedback.layer,
+        };
+
+        for backend in backends {
+            match backend {
+                Backend::Notification => {
+                    let text = &config.notifications;
+                    let body = match event {
+                        Event::Toggle if status.enabled => text.enabled_text.clone(),
+                        Event::Toggle => text.disabled_text.clone(),
+                        Event::Layer => text
+                            .layer_text
+                            .replace("{layer}", status.layer.as_deref().unwrap_or("base")),
+                    };
+                    self.notifier.notify(text, &body);
+                }
+                Backend::Bell => {
+                    // Two rings for enabling, one otherwise
+                    let bell = match event == Event::Toggle && status.enabled {
+                        true => "\x07\x07",
+                        false => "\x07",
+                    };
+                    print!("{}", bell);
+                    let _ = stdout().flush();
+                }
+                Backend::Led => self.flash_led(config, keyboards),
+                Backend::Command => self.run_command(config, event, status),
+                Backend::None => {}
+            }
+        }
+    }
+
+    /// Inverts the feedback LED on every keyboard until `tick` restores it
+    fn flash_led(&mut self, config: &Config, keyboards: &mut [Keyboard]) {
+        let Some(led) = parse_led(&config.feedback.led) else {
+            warn!("Invalid feedback LED: {}", config.feedback.led);
+            return;
+        };
+
+        for kb in keyboards.iter_mut() {
+            let on = shown(kb, led);
+            kb.set_led(led, !on);
+        }
+        let until = Instant::now() + Duration::from_millis(config.feedback.led_flash_ms);
+        self.flash = Some((led, until));
+    }
+
+    fn run_command(&mut self, config: &Config, event: Event, status: &Status) {
+        if config.feedback.command.is_empty() {
+            warn!("The command feedback backend needs a [feedback] command");
+            return;
+        }
+
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", &config.feedback.command])
+            .env("RK_EVENT", event.name())
+            .env("RK_ENABLED", if status.enabled { "1" } else { "0" })
+            .env("RK_LAYER", status.layer.as_deref().unwrap_or_default())
+            .env("RK_PROFILE", status.profile.as_deref().unwrap_or_default())
+            .env("RK_DEVICE", status.device.as_deref().unwrap_or_default());
+
+        // When run through sudo, run as the user who started rk
+        if geteuid().is_root() {
+            let id = |name| var(name).ok().and_then(|id| id.parse().ok());
+            if let (Some(uid), Some(gid)) = (id("SUDO_UID"), id("SUDO_GID")) {
+                command.uid(uid).gid(gid);
+            }
+        }
+
+        match command.spawn() {
+            Ok(child) => self.commands.push(child),
+            Err(e) => warn!("Failed to run feedback command: {}", e),
+        }
+    }
+
+    /// When `tick` next has an LED to restore
+    pub fn deadline(&self) -> Option<Instant> {
+        self.flash.map(|(_, until)| until)
+    }
+
+    /// Restores a flashed LED once its time is up and reaps finished commands
+    pub fn tick(&mut self, keyboards: &mut [Keyboard]) {
+        if let Some((led, until)) = self.flash {
+            if until <= Instant::now() {
+                for kb in keyboards.iter_mut() {
+                    let on = shown(kb, led);
+                    kb.set_led(led, on);
+                }
+                self.flash = None;
+            }
+        }
+
+        self.commands
+            .retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+    }
+
+    /// Sets the indicator LED of each keyboard to the state it should show
+    pub fn indicate(&self, config: &IndicatorConfig, keyboards: &mut [Keyboard]) {
+        let led = config.led.as_deref().and_then(parse_led);
+
+        for kb in keyboards.iter_mut() {
+            let remapper = &kb.remapper;
+            let on = match config.show {
+                Indicate::Enabled => remapper.enabled,
+                Indicate::Disabled => !remapper.enabled,
+                Indicate::Layer => remapper.active_layers.iter().any(|&l| {
+                    let name = &remapper.keymap.layers[l].name;
+                    config.layers.is_empty() || config.layers.contains(name)
+                }),
+            };
+            let indicator = led.map(|led| (led, on));
+            if kb.indicator == indicator {
+                continue;
+            }
+
+            // An LED no longer used as the indicator goes back to its lock state
+            if let Some((old, _)) = kb.indicator.filter(|&(old, _)| Some(old) != led) {
+                let on = kb.remapper.leds.contains(&old);
+                kb.set_led(old, on);
+            }
+            kb.indicator = None;
+
+            // A flash restores the LED itself, the indicator is set after it
+            if let Some((led, on)) = indicator {
+                if self.flash.is_some_and(|(flashing, _)| flashing == led) {
+                    continue;
+                }
+                kb.set_led(led, on);
+            }
+            kb.indicator = indicator;
+        }
+    }
+
+    /// Hands the indicator & flashed LEDs back to their lock states, before exiting
+    pub fn clear(&mut self, keyboards: &mut [Keyboard]) {
+        for kb in keyboards.iter_mut() {
+            let leds = kb.indicator.take().map(|(led, _)| led);
+            for led in leds.into_iter().chain(self.flash.map(|(led, _)| led)) {
+                let on = kb.remapper.leds.contains(&led);
+                kb.set_led(led, on);
+            }
+        }
+        self.flash = None;
+    }
+}
+
+/// The state an LED is showing, the indicator's or its lock state
+fn shown(kb: &Keyboard, led: LedCode) -> bool {
+    match kb.indicator {
+        Some((indicator, on)) if indicator == led => on,
+        _ => kb.remapper.leds.contains(&led),
+    }
+}
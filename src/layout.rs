@@ -0,0 +1,230 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+
+use evdev::KeyCode;
+
+use crate::{parse_combo, MacroStep};
+
+/// Base layout rows as (unshifted, shifted, key) for the US layout
+const US_KEYS: &[(char, char, KeyCode)] = &[
+    ('`', '~', KeyCode::KEY_GRAVE),
+    ('1', '!', KeyCode::KEY_1),
+    ('2', '@', KeyCode::KEY_2),
+    ('3', '#', KeyCode::KEY_3),
+    ('4', '$', KeyCode::KEY_4),
+    ('5', '%', KeyCode::KEY_5),
+    ('6', '^', KeyCode::KEY_6),
+    ('7', '&', KeyCode::KEY_7),
+    ('8', '*', KeyCode::KEY_8),
+    ('9', '(', KeyCode::KEY_9),
+    ('0', ')', KeyCode::KEY_0),
+    ('-', '_', KeyCode::KEY_MINUS),
+    ('=', '+', KeyCode::KEY_EQUAL),
+    ('q', 'Q', KeyCode::KEY_Q),
+    ('w', 'W', KeyCode::KEY_W),
+    ('e', 'E', KeyCode::KEY_E),
+    ('r', 'R', KeyCode::KEY_R),
+    ('t', 'T', KeyCode::KEY_T),
+    ('y', 'Y', KeyCode::KEY_Y),
+    ('u', 'U', KeyCode::KEY_U),
+    ('i', 'I', KeyCode::KEY_I),
+    ('o', 'O', KeyCode::KEY_O),
+    ('p', 'P', KeyCode::KEY_P),
+    ('[', '{', KeyCode::KEY_LEFTBRACE),
+    (']', '}', KeyCode::KEY_RIGHTBRACE),
+    ('\\', '|', KeyCode::KEY_BACKSLASH),
+    ('a', 'A', KeyCode::KEY_A),
+    ('s', 'S', KeyCode::KEY_S),
+    ('d', 'D', KeyCode::KEY_D),
+    ('f', 'F', KeyCode::KEY_F),
+    ('g', 'G', KeyCode::KEY_G),
+    ('h', 'H', KeyCode::KEY_H),
+    ('j', 'J', KeyCode::KEY_J),
+    ('k', 'K', KeyCode::KEY_K),
+    ('l', 'L', KeyCode::KEY_L),
+    (';', ':', KeyCode::KEY_SEMICOLON),
+    ('\'', '"', KeyCode::KEY_APOSTROPHE),
+    ('z', 'Z', KeyCode::KEY_Z),
+    ('x', 'X', KeyCode::KEY_X),
+    ('c', 'C', KeyCode::KEY_C),
+    ('v', 'V', KeyCode::KEY_V),
+    ('b', 'B', KeyCode::KEY_B),
+    ('n', 'N', KeyCode::KEY_N),
+    ('m', 'M', KeyCode::KEY_M),
+    (',', '<', KeyCode::KEY_COMMA),
+    ('.', '>', KeyCode::KEY_DOT),
+    ('/', '?', KeyCode::KEY_SLASH),
+];
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct TextConfig {
+    layout: String,
+    unicode_input: String,
+    keys: HashMap<char, String>,
+}
+
+impl Default for TextConfig {
+    fn default() -> Self {
+        Self {
+            layout: "us".into(),
+            unicode_input: "ibus".into(),
+            keys: HashMap::new(),
+        }
+    }
+}
+
+/// How characters missing from the layout are typed
+enum UnicodeInput {
+    /// Ctrl+Shift+U, the hex code point, then space (GTK & IBus)
+    Ibus,
+    None,
+}
+
+/// Resolves characters to the key combos that type them
+pub struct Layout {
+    keys: HashMap<char, (Vec<KeyCode>, KeyCode)>,
+    unicode_input: UnicodeInput,
+}
+
+impl Layout {
+    pub fn new(config: &TextConfig) -> Result<Self, Box<dyn Error>> {
+        let mut keys = HashMap::new();
+
+        match config.layout.to_lowercase().as_str() {
+            "us" => {
+                for &(lower, upper, key) in US_KEYS {
+                    keys.insert(lower, (vec![], key));
+                    keys.insert(upper, (vec![KeyCode::KEY_LEFTSHIFT], key));
+                }
+            }
+            "none" => {}
+            other => return Err(format!("Unknown text layout: {}", other).into()),
+        }
+
+        keys.insert(' ', (vec![], KeyCode::KEY_SPACE));
+        keys.insert('\n', (vec![], KeyCode::KEY_ENTER));
+        keys.insert('\t', (vec![], KeyCode::KEY_TAB));
+
+        for (ch, combo) in &config.keys {
+            let combo = parse_combo(combo)
+                .map_err(|e| format!("Invalid combo for '{}' in [text.keys]: {}", ch, e))?;
+            keys.insert(*ch, combo);
+        }
+
+        let unicode_input = match config.unicode_input.to_lowercase().as_str() {
+            "ibus" | "gtk" => UnicodeInput::Ibus,
+            "none" => UnicodeInput::None,
+            other => return Err(format!("Unknown unicode input method: {}", other).into()),
+        };
+
+        Ok(Self {
+            keys,
+            unicode_input,
+        })
+    }
+
+    /// Types a hex digit of a code point, through the layout if it has the digit & on the US
+    /// layout's keys otherwise
+    fn hex_digit(&self, digit: char) -> MacroStep {
+        match self.keys.get(&digit) {
+            Some((mods, key)) => MacroStep::Tap(mods.clone(), *key),
+            None => {
+                let (_, _, key) = US_KEYS
+                    .iter()
+                    .find(|(lower, ..)| *lower == digit)
+                    .expect("hex digits are on the US layout");
+                MacroStep::Tap(vec![], *key)
+            }
+        }
+    }
+
+    /// Builds the macro steps that type `text`, failing if a character can't be typed
+    pub fn type_text(&self, text: &str) -> Result<Vec<MacroStep>, String> {
+        let mut steps = Vec::new();
+
+        for ch in text.chars() {
+            if let Some((mods, key)) = self.keys.get(&ch) {
+                steps.push(MacroStep::Tap(mods.clone(), *key));
+                continue;
+            }
+
+            match self.unicode_input {
+                UnicodeInput::Ibus => {
+                    steps.push(MacroStep::Tap(
+                        vec![KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFTSHIFT],
+                        KeyCode::KEY_U,
+                    ));
+                    for digit in format!("{:x}", ch as u32).chars() {
+                        steps.push(self.hex_digit(digit));
+                    }
+                    steps.push(MacroStep::Tap(vec![], KeyCode::KEY_SPACE));
+                }
+                UnicodeInput::None => {
+                    return Err(format!("No key for '{}' and unicode input is disabled", ch))
+                }
+            }
+        }
+
+        Ok(steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_config(layout: &str, keys: &[(char, &str)]) -> TextConfig {
+        TextConfig {
+            layout: layout.into(),
+            unicode_input: "ibus".into(),
+            keys: keys.iter().map(|&(ch, combo)| (ch, combo.into())).collect(),
+        }
+    }
+
+    fn taps(steps: &[MacroStep]) -> Vec<(Vec<KeyCode>, KeyCode)> {
+        steps
+            .iter()
+            .filter_map(|step| match step {
+                MacroStep::Tap(mods, key) => Some((mods.clone(), *key)),
+                MacroStep::Delay(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unicode_input_types_the_code_point_in_hex() {
+        let layout = Layout::new(&text_config("us", &[])).unwrap();
+        assert_eq!(
+            taps(&layout.type_text("✓").unwrap()),
+            [
+                (
+                    vec![KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFTSHIFT],
+                    KeyCode::KEY_U
+                ),
+                (vec![], KeyCode::KEY_2),
+                (vec![], KeyCode::KEY_7),
+                (vec![], KeyCode::KEY_1),
+                (vec![], KeyCode::KEY_3),
+                (vec![], KeyCode::KEY_SPACE),
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_digits_follow_the_layout_keys() {
+        // An AZERTY digit row needs shift
+        let layout =
+            Layout::new(&text_config("none", &[('1', "shift+1"), ('3', "shift+3")])).unwrap();
+        assert_eq!(
+            taps(&layout.type_text("✓").unwrap())[1..5],
+            [
+                (vec![], KeyCode::KEY_2),
+                (vec![], KeyCode::KEY_7),
+                (vec![KeyCode::KEY_LEFTSHIFT], KeyCode::KEY_1),
+                (vec![KeyCode::KEY_LEFTSHIFT], KeyCode::KEY_3),
+            ]
+        );
+    }
+}
@@ -0,0 +1,57 @@
+use std::env::var;
+use std::io::{stderr, Write};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Logs to stderr, with syslog priority prefixes when stderr is connected to journald
+struct Logger {
+    journald: bool,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = if self.journald {
+            // journald reads <N> prefixes as the priority, see sd-daemon(3)
+            let priority = match record.level() {
+                Level::Error => 3,
+                Level::Warn => 4,
+                Level::Info => 6,
+                Level::Debug | Level::Trace => 7,
+            };
+            format!("<{}>{}\n", priority, record.args())
+        } else {
+            match record.level() {
+                Level::Error => format!("Error: {}\n", record.args()),
+                Level::Warn => format!("Warning: {}\n", record.args()),
+                Level::Info => format!("{}\n", record.args()),
+                Level::Debug | Level::Trace => format!("Debug: {}\n", record.args()),
+            }
+        };
+        let _ = stderr().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, with the level taken from $RK_LOG (error, warn, info or debug)
+pub fn init() {
+    let level = var("RK_LOG")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    let logger = Logger {
+        journald: var("JOURNAL_STREAM").is_ok(),
+    };
+
+    if log::set_logger(Box::leak(Box::new(logger))).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -1,24 +1,360 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::env::var;
 use std::error::Error;
-use std::fs::{read_dir, read_to_string};
-use std::process::Command;
-use std::thread::sleep;
-use std::time::Duration;
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
-use evdev::{Device, EventSummary, EventType, InputEvent, KeyCode, LedCode};
+use nix::errno::Errno;
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
+
+use log::{error, info, warn};
+
+use evdev::{
+    AttributeSet, Device, EventSummary, EventType, InputEvent, KeyCode, LedCode, UinputAbsSetup,
+};
+
+mod check;
+mod context;
+mod control;
+mod daemon;
+mod device;
+mod feedback;
+mod layout;
+mod logger;
+mod mouse;
+mod notify;
+mod socket;
+mod watch;
+
+use context::{ContextConfig, ContextWatcher};
+use control::{ControlConfig, ControlServer, Request};
+use daemon::PidFile;
+use device::{find_keyboards, glob_match, open_keyboard, DeviceConfig, DeviceFilter, Keyboard};
+use feedback::{Event, Feedback, FeedbackConfig, IndicatorConfig};
+use layout::{Layout, TextConfig};
+use mouse::{Direction, MouseConfig, Pointer};
+use notify::NotifyConfig;
+use watch::{ConfigWatcher, DeviceChange, DeviceWatcher, ShutdownWatcher};
+
+const DEFAULT_TAP_HOLD_TIMEOUT_MS: u64 = 200;
+const DEFAULT_COMBO_TIMEOUT_MS: u64 = 50;
+const DEFAULT_MACRO_DELAY_MS: u64 = 10;
+const VIRTUAL_DEVICE_NAME: &str = "rk";
+const VIRTUAL_POINTER_NAME: &str = "rk pointer";
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Config {
     toggle: String,
+    combo_timeout_ms: Option<u64>,
+    #[serde(default, deserialize_with = "mapping_sections")]
+    mappings: HashMap<String, HashMap<String, Target>>,
+    #[serde(default)]
+    layers: HashMap<String, HashMap<String, Target>>,
+    /// `[app."pattern"]` mappings, applying while a matching app is focused
+    #[serde(default, deserialize_with = "app_sections")]
+    app: BTreeMap<String, Sections>,
+    /// Named on/off states usable as conditions, with their initial values
+    #[serde(default)]
+    flags: BTreeMap<String, bool>,
+    #[serde(default)]
+    text: TextConfig,
+    #[serde(default)]
+    mouse: MouseConfig,
+    #[serde(default)]
+    device: DeviceConfig,
+    #[serde(default)]
+    devices: DeviceFilter,
+    #[serde(default)]
+    control: ControlConfig,
+    #[serde(default)]
+    notifications: NotifyConfig,
+    #[serde(default)]
+    feedback: FeedbackConfig,
+    #[serde(default)]
+    indicator: IndicatorConfig,
+    #[serde(default)]
+    context: ContextConfig,
+}
+
+/// Per-device settings, its mappings & layers replace the top-level ones
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    toggle: Option<String>,
+    combo_timeout_ms: Option<u64>,
+    #[serde(default, deserialize_with = "mapping_sections")]
+    mappings: HashMap<String, HashMap<String, Target>>,
     #[serde(default)]
-    mappings: HashMap<String, HashMap<String, String>>,
+    layers: HashMap<String, HashMap<String, Target>>,
+    #[serde(default, deserialize_with = "app_sections")]
+    app: BTreeMap<String, Sections>,
+}
+
+/// Mappings by section name, then by the keys they map
+type Sections = HashMap<String, HashMap<String, Target>>;
+
+/// An entry of a `[mappings.*]` section, a mapping or a section with more conditions
+enum MappingEntry {
+    Target(Target),
+    Section(HashMap<String, MappingEntry>),
+}
+
+impl<'de> Deserialize<'de> for MappingEntry {
+    /// Tables with any field of a mapping are mappings, so their errors aren't lost in
+    /// retrying them as sections
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = toml::Value::deserialize(deserializer)?;
+        let is_target = match &value {
+            toml::Value::Table(table) => table.keys().any(|k| TARGET_FIELDS.contains(&k.as_str())),
+            _ => true,
+        };
+
+        if is_target {
+            Target::deserialize(value)
+                .map(MappingEntry::Target)
+                .map_err(|e| de::Error::custom(e.message()))
+        } else {
+            HashMap::deserialize(value)
+                .map(MappingEntry::Section)
+                .map_err(|e| de::Error::custom(e.message()))
+        }
+    }
+}
+
+/// Adds a section's mappings to `sections`, nested sections under names like "numlock_off.capslock_on"
+fn flatten_section(
+    section: String,
+    entries: HashMap<String, MappingEntry>,
+    sections: &mut HashMap<String, HashMap<String, Target>>,
+) {
+    let mut targets = HashMap::new();
+    for (name, entry) in entries {
+        match entry {
+            MappingEntry::Target(target) => {
+                targets.insert(name, target);
+            }
+            MappingEntry::Section(entries) => {
+                flatten_section(format!("{}.{}", section, name), entries, sections)
+            }
+        }
+    }
+    sections.insert(section, targets);
+}
+
+/// Reads `[mappings.*]` sections, flattening nested ones
+fn mapping_sections<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, HashMap<String, Target>>, D::Error> {
+    let mut sections = HashMap::new();
+    for (section, entries) in
+        HashMap::<String, HashMap<String, MappingEntry>>::deserialize(deserializer)?
+    {
+        flatten_section(section, entries, &mut sections);
+    }
+    Ok(sections)
+}
+
+/// Reads `[app.*]` tables, whose own mappings form the "default" section
+fn app_sections<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, Sections>, D::Error> {
+    let apps = BTreeMap::<String, HashMap<String, MappingEntry>>::deserialize(deserializer)?;
+    Ok(apps
+        .into_iter()
+        .map(|(app, entries)| {
+            let mut sections = HashMap::new();
+            flatten_section("default".into(), entries, &mut sections);
+            (app, sections)
+        })
+        .collect())
+}
+
+enum Target {
+    Key(String),
+    TapHold {
+        tap: String,
+        hold: String,
+        timeout_ms: Option<u64>,
+    },
+    Macro {
+        steps: Vec<String>,
+        delay_ms: Option<u64>,
+    },
+    Text {
+        text: String,
+        delay_ms: Option<u64>,
+    },
+    MouseMove {
+        mouse_move: String,
+    },
+    Scroll {
+        scroll: String,
+    },
+    /// A key or chord, with what to do about the modifiers its rule requires
+    Remap {
+        key: String,
+        modifiers: ModifierMode,
+    },
+}
+
+const TARGET_FIELDS: &[&str] = &[
+    "tap",
+    "hold",
+    "timeout_ms",
+    "macro",
+    "delay_ms",
+    "text",
+    "mouse_move",
+    "scroll",
+    "key",
+    "modifiers",
+];
+
+/// A mapping written as a table, before checking its fields go together
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetTable {
+    tap: Option<String>,
+    hold: Option<String>,
+    timeout_ms: Option<u64>,
+    #[serde(rename = "macro")]
+    steps: Option<Vec<String>>,
+    delay_ms: Option<u64>,
+    text: Option<String>,
+    mouse_move: Option<String>,
+    scroll: Option<String>,
+    key: Option<String>,
+    modifiers: Option<ModifierMode>,
+}
+
+impl TryFrom<TargetTable> for Target {
+    type Error = String;
+
+    fn try_from(t: TargetTable) -> Result<Self, String> {
+        let kinds = [
+            ("tap & hold", t.tap.is_some() || t.hold.is_some()),
+            ("macro", t.steps.is_some()),
+            ("text", t.text.is_some()),
+            ("mouse_move", t.mouse_move.is_some()),
+            ("scroll", t.scroll.is_some()),
+            ("key", t.key.is_some()),
+        ];
+        let given: Vec<&str> = kinds.iter().filter(|(_, s)| *s).map(|(k, _)| *k).collect();
+        if given.len() != 1 {
+            return Err(format!(
+                "expected one of tap & hold, macro, text, mouse_move, scroll or key, found {}",
+                match given.is_empty() {
+                    true => "none".into(),
+                    false => given.join(", "),
+                }
+            ));
+        }
+
+        // Options only apply to the kind of mapping they belong to
+        let only = |set: bool, option: &str, kinds: &str| match set {
+            true => Err(format!("{} only applies to {}", option, kinds)),
+            false => Ok(()),
+        };
+        only(
+            t.tap.is_none() && t.timeout_ms.is_some(),
+            "timeout_ms",
+            "tap & hold",
+        )?;
+        only(
+            t.steps.is_none() && t.text.is_none() && t.delay_ms.is_some(),
+            "delay_ms",
+            "macro & text",
+        )?;
+        only(t.key.is_none() && t.modifiers.is_some(), "modifiers", "key")?;
+
+        Ok(match t {
+            TargetTable {
+                tap: Some(tap),
+                hold: Some(hold),
+                timeout_ms,
+                ..
+            } => Target::TapHold {
+                tap,
+                hold,
+                timeout_ms,
+            },
+            TargetTable { tap: Some(_), .. } => return Err("tap needs a hold".into()),
+            TargetTable { hold: Some(_), .. } => return Err("hold needs a tap".into()),
+            TargetTable {
+                steps: Some(steps),
+                delay_ms,
+                ..
+            } => Target::Macro { steps, delay_ms },
+            TargetTable {
+                text: Some(text),
+                delay_ms,
+                ..
+            } => Target::Text { text, delay_ms },
+            TargetTable {
+                mouse_move: Some(mouse_move),
+                ..
+            } => Target::MouseMove { mouse_move },
+            TargetTable {
+                scroll: Some(scroll),
+                ..
+            } => Target::Scroll { scroll },
+            TargetTable {
+                key: Some(key),
+                modifiers,
+                ..
+            } => Target::Remap {
+                key,
+                modifiers: modifiers.unwrap_or_default(),
+            },
+            _ => unreachable!("one kind is set"),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Target {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TargetVisitor;
+
+        impl<'de> Visitor<'de> for TargetVisitor {
+            type Value = Target;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a key or a mapping table")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Target, E> {
+                Ok(Target::Key(s.into()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Target, A::Error> {
+                let table = TargetTable::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Target::try_from(table).map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(TargetVisitor)
+    }
+}
+
+/// Whether modifiers a rule requires stay down on the virtual device while it's held
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ModifierMode {
+    /// `shift+backspace = "delete"` sends a plain Delete
+    #[default]
+    Consume,
+    /// `shift+backspace = "delete"` sends Shift+Delete
+    Pass,
 }
 
 impl Config {
-    fn load() -> Result<Self, Box<dyn Error>> {
+    fn find() -> Result<String, Box<dyn Error>> {
         let paths = [
             var("RK_CONFIG").ok(),
             Some("rk.toml".into()),
@@ -26,11 +362,8 @@ impl Config {
             Some("/etc/rk.toml".into()),
         ];
 
-        for path in paths.iter().flatten() {
-            if let Ok(content) = read_to_string(path) {
-                println!("Loaded config: {}", path);
-                return Ok(toml::from_str(&content)?);
-            }
+        if let Some(path) = paths.into_iter().flatten().find(|p| Path::new(p).is_file()) {
+            return Ok(path);
         }
 
         Err(
@@ -38,11 +371,41 @@ impl Config {
                 .into(),
         )
     }
+
+    fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(&read_to_string(path)?)?)
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Target::Key(key) => write!(f, "{}", key),
+            Target::TapHold { tap, hold, .. } => write!(f, "{{ tap = {}, hold = {} }}", tap, hold),
+            Target::Macro { steps, .. } => write!(f, "{{ macro = [{}] }}", steps.join(", ")),
+            Target::Text { text, .. } => write!(f, "{{ text = {:?} }}", text),
+            Target::MouseMove { mouse_move } => write!(f, "{{ mouse_move = {} }}", mouse_move),
+            Target::Scroll { scroll } => write!(f, "{{ scroll = {} }}", scroll),
+            Target::Remap { key, modifiers } => match modifiers {
+                ModifierMode::Consume => write!(f, "{{ key = {}, modifiers = consume }}", key),
+                ModifierMode::Pass => write!(f, "{{ key = {}, modifiers = pass }}", key),
+            },
+        }
+    }
 }
 
 fn parse_keycode(s: &str) -> Option<KeyCode> {
+    // Modifier shorthands resolve to the left-hand key
+    if let Some(modifier) = Modifier::parse(s) {
+        return Some(modifier.keys()[0]);
+    }
+
+    // Mouse buttons keep their own prefix, e.g. btn_left
     let normalized = s.to_uppercase().trim_start_matches("KEY_").to_string();
-    let key_name = format!("KEY_{}", normalized);
+    let key_name = match normalized.starts_with("BTN_") {
+        true => normalized,
+        false => format!("KEY_{}", normalized),
+    };
 
     for code in 0u16..=767 {
         let keycode = KeyCode(code);
@@ -54,6 +417,14 @@ fn parse_keycode(s: &str) -> Option<KeyCode> {
 }
 
 fn parse_led(s: &str) -> Option<LedCode> {
+    // Lock key names resolve to their LEDs
+    let s = match s.to_lowercase().as_str() {
+        "numlock" => "numl",
+        "capslock" => "capsl",
+        "scrolllock" => "scrolll",
+        _ => s,
+    };
+
     let normalized = s.to_uppercase().trim_start_matches("LED_").to_string();
     let led_name = format!("LED_{}", normalized);
 
@@ -66,14 +437,27 @@ fn parse_led(s: &str) -> Option<LedCode> {
     None
 }
 
-fn parse_toggle(s: &str) -> Result<(Vec<KeyCode>, KeyCode), Box<dyn Error>> {
+/// The name `parse_led` accepts for an LED, e.g. "numlock"
+fn led_name(led: LedCode) -> String {
+    match led {
+        LedCode::LED_NUML => "numlock".into(),
+        LedCode::LED_CAPSL => "capslock".into(),
+        LedCode::LED_SCROLLL => "scrolllock".into(),
+        _ => format!("{:?}", led)
+            .trim_start_matches("LED_")
+            .to_lowercase(),
+    }
+}
+
+fn parse_combo(s: &str) -> Result<(Vec<KeyCode>, KeyCode), Box<dyn Error>> {
     let parts: Vec<&str> = s.split('+').map(|p| p.trim()).collect();
 
     if parts.is_empty() {
-        return Err("Empty toggle".into());
+        return Err("Empty key combo".into());
     }
 
-    let key = parse_keycode(parts.last().unwrap()).ok_or("Invalid key in toggle combo")?;
+    let last = parts.last().unwrap();
+    let key = parse_keycode(last).ok_or_else(|| format!("Invalid key: {}", last))?;
 
     let modifiers: Result<Vec<_>, Box<dyn Error>> = parts[..parts.len() - 1]
         .iter()
@@ -83,232 +467,2269 @@ fn parse_toggle(s: &str) -> Result<(Vec<KeyCode>, KeyCode), Box<dyn Error>> {
     Ok((modifiers?, key))
 }
 
-fn parse_condition(s: &str) -> Option<(LedCode, bool)> {
-    if let Some(led_name) = s.strip_suffix("_on") {
-        parse_led(led_name).map(|led| (led, true))
-    } else if let Some(led_name) = s.strip_suffix("_off") {
-        parse_led(led_name).map(|led| (led, false))
+/// A state a `[mappings.*]` section can require to be on or off
+#[derive(Clone, Copy, PartialEq)]
+enum Condition {
+    Led(LedCode),
+    /// Index into `Config::flags`
+    Flag(usize),
+    /// Index into `Keymap::apps`, met while a matching app is focused
+    App(usize),
+    /// Met while either key of the modifier is down
+    Modifier(Modifier),
+}
+
+impl Condition {
+    /// Orders conditions, LEDs first
+    fn sort_key(self) -> (u8, usize) {
+        match self {
+            Condition::Led(led) => (0, led.0 as usize),
+            Condition::Flag(flag) => (1, flag),
+            Condition::App(app) => (2, app),
+            Condition::Modifier(modifier) => (3, modifier as usize),
+        }
+    }
+
+    fn name(self, flag_names: &[String], app_names: &[String]) -> String {
+        match self {
+            Condition::Led(led) => led_name(led),
+            Condition::Flag(flag) => flag_names[flag].clone(),
+            Condition::App(app) => format!("app \"{}\"", app_names[app]),
+            Condition::Modifier(modifier) => modifier.name().into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Modifier {
+    Ctrl,
+    Shift,
+    Alt,
+    Super,
+}
+
+impl Modifier {
+    const ALL: [Modifier; 4] = [
+        Modifier::Ctrl,
+        Modifier::Shift,
+        Modifier::Alt,
+        Modifier::Super,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Modifier::Ctrl => "ctrl",
+            Modifier::Shift => "shift",
+            Modifier::Alt => "alt",
+            Modifier::Super => "super",
+        }
+    }
+
+    fn keys(self) -> [KeyCode; 2] {
+        match self {
+            Modifier::Ctrl => [KeyCode::KEY_LEFTCTRL, KeyCode::KEY_RIGHTCTRL],
+            Modifier::Shift => [KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_RIGHTSHIFT],
+            Modifier::Alt => [KeyCode::KEY_LEFTALT, KeyCode::KEY_RIGHTALT],
+            Modifier::Super => [KeyCode::KEY_LEFTMETA, KeyCode::KEY_RIGHTMETA],
+        }
+    }
+
+    /// Parses a shorthand like `ctrl`, also used for keys, so `when_meta` works like `meta+a`
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "ctrl" | "control" => Some(Modifier::Ctrl),
+            "shift" => Some(Modifier::Shift),
+            "alt" => Some(Modifier::Alt),
+            "super" | "meta" | "win" => Some(Modifier::Super),
+            _ => None,
+        }
+    }
+
+    /// The modifier a key belongs to, either side counts
+    fn of(key: KeyCode) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.keys().contains(&key))
+    }
+}
+
+/// Parses `<led>_on`, `<flag>_off`, `when_<modifier>` etc., flags taking precedence over LEDs of
+/// the same name
+fn parse_condition(s: &str, flag_names: &[String]) -> Result<(Condition, bool), String> {
+    if let Some(name) = s.strip_prefix("when_") {
+        return Modifier::parse(name)
+            .map(|modifier| (Condition::Modifier(modifier), true))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown modifier: {} (expected ctrl, shift, alt or super/meta)",
+                    name
+                )
+            });
+    }
+
+    let (name, on) = if let Some(name) = s.strip_suffix("_on") {
+        (name, true)
+    } else if let Some(name) = s.strip_suffix("_off") {
+        (name, false)
     } else {
-        None
+        return Err(format!(
+            "Unknown condition: {} (expected <led or flag>_on, <led or flag>_off or when_<modifier>)",
+            s
+        ));
+    };
+
+    if let Some(flag) = flag_names.iter().position(|f| f == name) {
+        return Ok((Condition::Flag(flag), on));
+    }
+    parse_led(name)
+        .map(|led| (Condition::Led(led), on))
+        .ok_or_else(|| format!("Unknown LED or flag: {}", name))
+}
+
+/// Parses a `[mappings.*]` section name into the conditions it requires
+fn parse_conditions(
+    section: &str,
+    flag_names: &[String],
+) -> Result<Vec<(Condition, bool)>, String> {
+    section
+        .split('.')
+        .filter(|&s| s != "default")
+        .map(|s| parse_condition(s, flag_names))
+        .collect()
+}
+
+#[derive(Clone, PartialEq)]
+enum Action {
+    Key(KeyCode),
+    Chord(Vec<KeyCode>, KeyCode),
+    TapHold {
+        tap: KeyCode,
+        hold: KeyCode,
+        timeout: Duration,
+    },
+    LayerHold(usize),
+    LayerToggle(usize),
+    LayerOneshot(usize),
+    FlagHold(usize),
+    FlagToggle(usize),
+    Macro {
+        steps: Vec<MacroStep>,
+        delay: Duration,
+        /// Typed text, which modifiers held meanwhile would change
+        text: bool,
+    },
+    MouseMove(Direction),
+    Scroll(Direction),
+}
+
+#[derive(Clone, PartialEq)]
+enum MacroStep {
+    Tap(Vec<KeyCode>, KeyCode),
+    Delay(Duration),
+}
+
+impl MacroStep {
+    /// Parses a key combo like `ctrl+c` or a pause like `delay:50`
+    fn parse(s: &str) -> Result<Self, String> {
+        if let Some(ms) = s.trim().strip_prefix("delay:") {
+            return ms
+                .trim()
+                .parse()
+                .map(|ms| MacroStep::Delay(Duration::from_millis(ms)))
+                .map_err(|_| format!("Invalid delay: {}", s));
+        }
+
+        let (mods, key) = parse_combo(s).map_err(|e| e.to_string())?;
+        Ok(MacroStep::Tap(mods, key))
+    }
+}
+
+impl Action {
+    fn parse(
+        target: &Target,
+        layer_names: &[String],
+        flag_names: &[String],
+        layout: &Layout,
+    ) -> Result<Self, String> {
+        let key = |s: &str| parse_keycode(s).ok_or_else(|| format!("Invalid key: {}", s));
+
+        match target {
+            Target::Key(s) => {
+                if let Some(action) = parse_state_action(s, layer_names, flag_names)? {
+                    return Ok(action);
+                }
+
+                match parse_combo(s).map_err(|e| e.to_string())? {
+                    (mods, key) if mods.is_empty() => Ok(Action::Key(key)),
+                    (mods, key) => Ok(Action::Chord(mods, key)),
+                }
+            }
+            Target::MouseMove { mouse_move } => {
+                Ok(Action::MouseMove(Direction::parse(mouse_move)?))
+            }
+            Target::Scroll { scroll } => Ok(Action::Scroll(Direction::parse(scroll)?)),
+            Target::Remap { key, .. } => {
+                Self::parse(&Target::Key(key.clone()), layer_names, flag_names, layout)
+            }
+            Target::TapHold {
+                tap,
+                hold,
+                timeout_ms,
+            } => Ok(Action::TapHold {
+                tap: key(tap)?,
+                hold: key(hold)?,
+                timeout: Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TAP_HOLD_TIMEOUT_MS)),
+            }),
+            Target::Macro { steps, delay_ms } => Ok(Action::Macro {
+                steps: steps
+                    .iter()
+                    .map(|s| MacroStep::parse(s))
+                    .collect::<Result<_, _>>()?,
+                delay: Duration::from_millis(delay_ms.unwrap_or(DEFAULT_MACRO_DELAY_MS)),
+                text: false,
+            }),
+            Target::Text { text, delay_ms } => Ok(Action::Macro {
+                steps: layout.type_text(text)?,
+                delay: Duration::from_millis(delay_ms.unwrap_or(DEFAULT_MACRO_DELAY_MS)),
+                text: true,
+            }),
+        }
+    }
+
+    /// True if the action moves the pointer, scrolls or presses mouse buttons
+    fn uses_mouse(&self) -> bool {
+        let button = |key: &KeyCode| mouse::BUTTONS.contains(key);
+        match self {
+            Action::MouseMove(_) | Action::Scroll(_) => true,
+            Action::Key(key) => button(key),
+            Action::Chord(mods, key) => mods.iter().any(button) || button(key),
+            Action::TapHold { tap, hold, .. } => button(tap) || button(hold),
+            Action::Macro { steps, .. } => steps.iter().any(|step| {
+                matches!(step, MacroStep::Tap(mods, key) if mods.iter().any(button) || button(key))
+            }),
+            _ => false,
+        }
+    }
+}
+
+/// Parses `layer_hold(name)`, `layer_toggle(name)`, `layer_oneshot(name)`,
+/// `flag_hold(name)` and `flag_toggle(name)`
+///
+/// Returns None if `s` isn't a layer or flag action at all
+fn parse_state_action(
+    s: &str,
+    layer_names: &[String],
+    flag_names: &[String],
+) -> Result<Option<Action>, String> {
+    let Some((func, name)) = s.trim().strip_suffix(')').and_then(|s| s.split_once('(')) else {
+        return Ok(None);
+    };
+
+    let name = name.trim();
+    let layer = || {
+        layer_names
+            .iter()
+            .position(|n| n == name)
+            .ok_or_else(|| format!("Unknown layer: {}", name))
+    };
+    let flag = || {
+        flag_names
+            .iter()
+            .position(|n| n == name)
+            .ok_or_else(|| format!("Unknown flag: {}", name))
+    };
+
+    match func.trim() {
+        "layer_hold" => Ok(Some(Action::LayerHold(layer()?))),
+        "layer_toggle" => Ok(Some(Action::LayerToggle(layer()?))),
+        "layer_oneshot" => Ok(Some(Action::LayerOneshot(layer()?))),
+        "flag_hold" => Ok(Some(Action::FlagHold(flag()?))),
+        "flag_toggle" => Ok(Some(Action::FlagToggle(flag()?))),
+        other => Err(format!("Unknown action: {}", other)),
     }
 }
 
+/// Maps a single key, or a combo of keys pressed together, to an action
 struct MappingRule {
-    from: KeyCode,
-    to: KeyCode,
-    led_conditions: Vec<(LedCode, bool)>,
+    /// The config entry, for listing rules
+    source: String,
+    from: Vec<KeyCode>,
+    action: Action,
+    conditions: Vec<(Condition, bool)>,
+    /// Required modifiers to release on the virtual device while the key is held
+    consume: Vec<Modifier>,
 }
 
 impl MappingRule {
-    fn matches(&self, key: KeyCode, leds: &[LedCode]) -> bool {
-        if self.from != key {
+    fn matches(&self, key: KeyCode, remapper: &KeyRemapper) -> bool {
+        if self.from != [key] {
             return false;
         }
 
-        self.led_conditions
+        self.conditions_met(remapper)
+    }
+
+    fn conditions_met(&self, remapper: &KeyRemapper) -> bool {
+        self.conditions
             .iter()
-            .all(|(led, should_be_on)| leds.contains(led) == *should_be_on)
+            .all(|&(condition, should_be_on)| remapper.condition(condition) == should_be_on)
+    }
+
+    fn is_combo(&self) -> bool {
+        self.from.len() > 1
     }
 }
 
-struct KeyRemapper {
-    virtual_kbd: VirtualDevice,
-    enabled: bool,
-    held_keys: HashMap<KeyCode, bool>,
-    leds: Vec<LedCode>,
+struct Layer {
+    name: String,
+    rules: Vec<MappingRule>,
+}
+
+/// A one-shot layer stays active until the next key pressed on it is released
+struct Oneshot {
+    layer: usize,
+    consumer: Option<KeyCode>,
+}
+
+/// Combo keys pressed so far, held back until the combo completes or the window expires
+struct PendingCombo {
+    keys: Vec<KeyCode>,
+    deadline: Instant,
+}
+
+/// A triggered combo, its action is released with the first of its keys
+struct ActiveCombo {
+    trigger: KeyCode,
+    keys: Vec<KeyCode>,
+    action: Action,
+    released: bool,
+}
+
+/// A dual-role key that is either still undecided or has resolved to its hold key
+#[derive(Clone, Copy)]
+enum TapHoldState {
+    Pending {
+        tap: KeyCode,
+        hold: KeyCode,
+        deadline: Instant,
+    },
+    Held(KeyCode),
+}
+
+/// Everything parsed from the config, swapped as a whole on reload
+struct Keymap {
     toggle_mods: Vec<KeyCode>,
     toggle_key: KeyCode,
     rules: Vec<MappingRule>,
+    layers: Vec<Layer>,
+    /// Flag names & initial values, shared by every profile
+    flags: Vec<(String, bool)>,
+    /// `[app.*]` patterns, matched against the focused app
+    apps: Vec<String>,
+    combo_timeout: Duration,
+    mouse: MouseConfig,
 }
 
-impl KeyRemapper {
-    fn new(template: &Device, config: &Config) -> Result<Self, Box<dyn Error>> {
-        #[allow(deprecated)]
-        let mut virt_kbd = VirtualDeviceBuilder::new()?.name("rk");
-
-        if let Some(keys) = template.supported_keys() {
-            virt_kbd = virt_kbd.with_keys(&keys)?;
-        }
+impl Keymap {
+    fn new(config: &Config, profile: Option<&Profile>) -> Result<Self, Box<dyn Error>> {
+        let toggle = profile
+            .and_then(|p| p.toggle.as_ref())
+            .unwrap_or(&config.toggle);
+        let combo_timeout_ms = profile
+            .and_then(|p| p.combo_timeout_ms)
+            .or(config.combo_timeout_ms);
+        let (config_mappings, config_layers, config_apps) = match profile {
+            Some(p) => (&p.mappings, &p.layers, &p.app),
+            None => (&config.mappings, &config.layers, &config.app),
+        };
 
-        let leds = template.get_led_state()?.into_iter().collect();
-        let (toggle_mods, toggle_key) = parse_toggle(&config.toggle)?;
+        let (toggle_mods, toggle_key) = parse_combo(toggle)?;
 
+        let layer_names: Vec<String> = config_layers.keys().cloned().collect();
+        let flag_names: Vec<String> = config.flags.keys().cloned().collect();
+        let apps: Vec<String> = config_apps.keys().cloned().collect();
+        let layout = Layout::new(&config.text)?;
         let mut rules = Vec::new();
 
-        for (section, mappings) in &config.mappings {
-            let led_conditions = if section == "default" {
-                vec![]
-            } else {
-                section.split('.').filter_map(parse_condition).collect()
-            };
+        // App sections are mappings sections with the focused app as one more condition
+        let mut sections: Vec<_> = config_mappings
+            .iter()
+            .map(|(section, mappings)| (format!("mappings.{}", section), section, mappings, None))
+            .collect();
+        for (app, (name, app_sections)) in config_apps.iter().enumerate() {
+            for (section, mappings) in app_sections {
+                let context = match section.as_str() {
+                    "default" => format!("app.\"{}\"", name),
+                    _ => format!("app.\"{}\".{}", name, section),
+                };
+                sections.push((context, section, mappings, Some(app)));
+            }
+        }
 
-            for (from, to) in mappings {
-                match (parse_keycode(from), parse_keycode(to)) {
-                    (Some(from_key), Some(to_key)) => {
-                        rules.push(MappingRule {
-                            from: from_key,
-                            to: to_key,
-                            led_conditions: led_conditions.clone(),
-                        });
-                    }
-                    _ => {
-                        eprintln!(
-                            "Warning: Invalid mapping in [mappings.{}]: {} -> {}",
-                            section, from, to
-                        );
-                    }
+        for (context, section, mappings, app) in sections {
+            let mut conditions = match parse_conditions(section, &flag_names) {
+                Ok(conditions) => conditions,
+                Err(e) => {
+                    warn!("Skipping [{}]: {}", context, e);
+                    continue;
                 }
-            }
+            };
+            conditions.extend(app.map(|app| (Condition::App(app), true)));
+
+            rules.extend(parse_rules(
+                &context,
+                mappings,
+                &conditions,
+                &layer_names,
+                &flag_names,
+                &layout,
+            ));
         }
 
+        // Sections with more conditions are more specific & take precedence, app sections win ties
+        rules.sort_by_key(|r| {
+            let app = r
+                .conditions
+                .iter()
+                .any(|(c, _)| matches!(c, Condition::App(_)));
+            (std::cmp::Reverse(r.conditions.len()), !app)
+        });
+
+        let layers = layer_names
+            .iter()
+            .map(|name| Layer {
+                name: name.clone(),
+                rules: parse_rules(
+                    &format!("layers.{}", name),
+                    &config_layers[name],
+                    &[],
+                    &layer_names,
+                    &flag_names,
+                    &layout,
+                ),
+            })
+            .collect();
+
         Ok(Self {
-            virtual_kbd: virt_kbd.build()?,
-            enabled: false,
-            held_keys: HashMap::new(),
-            leds,
             toggle_mods,
             toggle_key,
             rules,
+            layers,
+            flags: config.flags.clone().into_iter().collect(),
+            apps,
+            combo_timeout: Duration::from_millis(
+                combo_timeout_ms.unwrap_or(DEFAULT_COMBO_TIMEOUT_MS),
+            ),
+            mouse: config.mouse.clone(),
         })
     }
 
-    fn is_toggle_pressed(&self, key: KeyCode) -> bool {
-        key == self.toggle_key
-            && self
-                .toggle_mods
-                .iter()
-                .all(|m| self.held_keys.get(m).copied().unwrap_or(false))
-    }
-
-    fn update_led(&mut self, key: KeyCode) {
-        let led = match key {
-            KeyCode::KEY_NUMLOCK => LedCode::LED_NUML,
-            KeyCode::KEY_CAPSLOCK => LedCode::LED_CAPSL,
-            KeyCode::KEY_SCROLLLOCK => LedCode::LED_SCROLLL,
-            _ => return,
-        };
-
-        if self.leds.contains(&led) {
-            self.leds.retain(|&l| l != led);
-        } else {
-            self.leds.push(led);
-        }
+    fn layer_index(&self, name: &str) -> Option<usize> {
+        self.layers.iter().position(|l| l.name == name)
     }
 
-    fn remap_key(&self, key: KeyCode) -> Option<KeyCode> {
-        if !self.enabled {
-            return None;
-        }
-
+    fn uses_mouse(&self) -> bool {
         self.rules
             .iter()
-            .find(|r| r.matches(key, &self.leds))
-            .map(|r| r.to)
+            .chain(self.layers.iter().flat_map(|l| &l.rules))
+            .any(|r| r.action.uses_mouse())
     }
+}
 
-    fn process_event(&mut self, event: &InputEvent) -> Result<(), Box<dyn Error>> {
-        if let EventSummary::Key(_, key, value) = event.destructure() {
-            if value == 1 {
-                self.update_led(key);
-            }
+/// State that applies to every keyboard, kept by the main loop so it outlives unplugging them all
+///
+/// Each remapper mirrors it, as its keys are mapped by it.
+#[derive(Clone, PartialEq)]
+struct SharedState {
+    enabled: bool,
+    /// Flag values by name
+    flags: BTreeMap<String, bool>,
+    /// The focused app, its Wayland app ID or X11 class
+    app: Option<String>,
+}
 
-            if value == 1 || value == 2 {
-                self.held_keys.insert(key, true);
-            } else if value == 0 {
-                self.held_keys.insert(key, false);
-            }
+impl SharedState {
+    fn new(config: &Config) -> Self {
+        Self {
+            enabled: false,
+            flags: config.flags.clone(),
+            app: None,
+        }
+    }
 
-            if value == 1 && self.is_toggle_pressed(key) {
-                self.enabled = !self.enabled;
-                self.notify();
-                return Ok(());
-            }
+    /// Keeps the values of flags still in the config, new ones start at their initial value
+    fn reload(&mut self, config: &Config) {
+        self.flags = config
+            .flags
+            .iter()
+            .map(|(name, &on)| (name.clone(), self.flags.get(name).copied().unwrap_or(on)))
+            .collect();
+    }
 
-            let event_to_emit = self
-                .remap_key(key)
-                .map(|remapped| InputEvent::new(EventType::KEY.0, remapped.0, value))
-                .unwrap_or(*event);
-            self.virtual_kbd.emit(&[event_to_emit])?;
-        } else {
-            self.virtual_kbd.emit(&[*event])?;
+    /// Takes on a keyboard's changes to the shared state & passes them on to every keyboard
+    fn sync(&mut self, keyboards: &mut [Keyboard], changed: Option<usize>) {
+        if let Some(state) = changed.map(|i| keyboards[i].remapper.shared()) {
+            *self = state;
         }
-        Ok(())
+        keyboards
+            .iter_mut()
+            .for_each(|kb| kb.remapper.set_shared(self));
     }
+}
 
-    fn notify(&self) {
-        let (msg, beep) = if self.enabled {
-            ("Enabled", "\x07\x07")
-        } else {
-            ("Disabled", "\x07")
-        };
+/// Where a remapper's events go, the virtual keyboard outside of tests
+trait Output {
+    fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()>;
+}
 
-        if let (Ok(user), Ok(uid)) = (var("SUDO_USER"), var("SUDO_UID")) {
-            if Command::new("sudo")
-                .args([
-                    "-u",
-                    &user,
-                    "sh",
-                    "-c",
-                    &format!(
-                        "DBUS_SESSION_BUS_ADDRESS=unix:path=/run/user/{}/bus notify-send -t 1500 'rk' '{}'",
-                        uid, msg
-                    ),
-                ])
-                .spawn()
-                .is_err()
-            {
-                print!("{}", beep);
-            }
-        }
+impl Output for VirtualDevice {
+    fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        VirtualDevice::emit(self, events)
     }
 }
 
-fn find_keyboards() -> Result<Vec<Device>, Box<dyn Error>> {
-    let mut keyboards = Vec::new();
+struct KeyRemapper {
+    virtual_kbd: Box<dyn Output>,
+    enabled: bool,
+    held_keys: HashMap<KeyCode, bool>,
+    /// Keys currently down on the virtual device
+    pressed: HashSet<KeyCode>,
+    /// The action each held key was pressed with, so toggling or LED changes can't strand outputs
+    key_actions: HashMap<KeyCode, Option<Action>>,
+    /// Modifiers released for a held key of a consuming rule
+    consumed: HashMap<KeyCode, Vec<KeyCode>>,
+    /// Consumed modifiers still held once their rule's key is released, only pressed again for
+    /// the next key so apps don't see a lone modifier tap
+    lapsed: Vec<KeyCode>,
+    /// Lock LEDs that are on, as reported by the device
+    leds: Vec<LedCode>,
+    /// Set once the device reports LED changes, until then they're guessed from lock keys
+    leds_reported: bool,
+    keymap: Keymap,
+    tap_holds: HashMap<KeyCode, TapHoldState>,
+    active_layers: Vec<usize>,
+    layer_keys: HashMap<KeyCode, usize>,
+    /// Flag states, indexed like `keymap.flags`
+    flags: Vec<bool>,
+    flag_keys: HashMap<KeyCode, usize>,
+    /// The focused app, its Wayland app ID or X11 class
+    app: Option<String>,
+    oneshot: Option<Oneshot>,
+    pending_combo: Option<PendingCombo>,
+    active_combos: Vec<ActiveCombo>,
+    /// Macro steps still to play, with the delay after them & whether they type text
+    macro_queue: VecDeque<(MacroStep, Duration, bool)>,
+    /// Modifiers released while text is typed, pressed again afterwards if still held
+    text_released: Vec<KeyCode>,
+    macro_resume: Instant,
+    pointer: Pointer,
+    /// Whether mouse buttons go to `virtual_kbd`, which copied the axes of a pointing device
+    own_buttons: bool,
+}
 
-    for entry in read_dir("/dev/input")? {
-        let path = entry?.path();
+impl KeyRemapper {
+    fn new(template: &Device, keymap: Keymap) -> Result<Self, Box<dyn Error>> {
+        #[allow(deprecated)]
+        let mut virt_kbd = VirtualDeviceBuilder::new()?.name(VIRTUAL_DEVICE_NAME);
 
-        if !path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .map_or(false, |n| n.starts_with("event"))
-        {
-            continue;
+        // Included mice & the like keep their axes, and their buttons to go with them
+        let has_axes = template.supported_relative_axes().is_some()
+            || template.supported_absolute_axes().is_some();
+        if let Some(axes) = template.supported_relative_axes() {
+            virt_kbd = virt_kbd.with_relative_axes(axes)?;
         }
-
-        if let Ok(dev) = Device::open(&path) {
-            if dev.supported_keys().map_or(false, |keys| {
-                keys.contains(KeyCode::KEY_A) && !keys.contains(KeyCode::BTN_LEFT)
-            }) {
-                println!("Found: {} ({:?})", dev.name().unwrap_or("Unknown"), path);
-                keyboards.push(dev);
-            }
+        for (axis, info) in template.get_absinfo()? {
+            virt_kbd = virt_kbd.with_absolute_axis(&UinputAbsSetup::new(axis, info))?;
         }
+        virt_kbd = virt_kbd.with_properties(template.properties())?;
+
+        // Otherwise mouse buttons go to the pointer, which has its own device
+        let keys: AttributeSet<KeyCode> = template
+            .supported_keys()
+            .map(|keys| {
+                keys.iter()
+                    .filter(|k| has_axes || !mouse::BUTTONS.contains(k))
+                    .collect()
+            })
+            .unwrap_or_default();
+        virt_kbd = virt_kbd.with_keys(&keys)?;
+
+        let leds = template.get_led_state()?.into_iter().collect();
+        let mut remapper = Self::with_output(Box::new(virt_kbd.build()?), leds, keymap)?;
+        remapper.own_buttons = has_axes;
+        Ok(remapper)
     }
 
-    keyboards
-        .is_empty()
-        .then(|| Err("No keyboards found".into()))
-        .unwrap_or(Ok(keyboards))
-}
+    /// A remapper writing to `output`, starting from the lock LEDs that are on
+    fn with_output(
+        output: Box<dyn Output>,
+        leds: Vec<LedCode>,
+        keymap: Keymap,
+    ) -> Result<Self, Box<dyn Error>> {
+        let flags = keymap.flags.iter().map(|&(_, on)| on).collect();
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let config = Config::load()?;
-    let mut keyboards = find_keyboards()?;
-    let mut remapper = KeyRemapper::new(&keyboards[0], &config)?;
-    keyboards.iter_mut().try_for_each(|kb| kb.grab())?;
+        // Created up front when it's needed, as clients miss the first events of a new device
+        let mut pointer = Pointer::default();
+        if keymap.uses_mouse() {
+            pointer.open()?;
+        }
 
-    println!("Loaded {} mapping rules", remapper.rules.len());
-    println!("Press {} to toggle remapping", config.toggle);
+        Ok(Self {
+            virtual_kbd: output,
+            enabled: false,
+            held_keys: HashMap::new(),
+            pressed: HashSet::new(),
+            key_actions: HashMap::new(),
+            consumed: HashMap::new(),
+            lapsed: Vec::new(),
+            leds,
+            leds_reported: false,
+            keymap,
+            tap_holds: HashMap::new(),
+            active_layers: Vec::new(),
+            layer_keys: HashMap::new(),
+            flags,
+            flag_keys: HashMap::new(),
+            app: None,
+            oneshot: None,
+            pending_combo: None,
+            active_combos: Vec::new(),
+            macro_queue: VecDeque::new(),
+            text_released: Vec::new(),
+            macro_resume: Instant::now(),
+            pointer,
+            own_buttons: false,
+        })
+    }
 
-    loop {
-        for kb in &mut keyboards {
-            if let Ok(mut events) = kb.fetch_events() {
-                events.try_for_each(|e| remapper.process_event(&e))?;
-            }
+    /// Opens what `keymap` needs, so swapping it in can't fail halfway through a reload
+    fn prepare_keymap(&mut self, keymap: &Keymap) -> Result<(), Box<dyn Error>> {
+        if keymap.uses_mouse() {
+            self.pointer.open()?;
         }
+        Ok(())
+    }
+
+    /// Swaps in a prepared keymap, keeping held keys, enabled state and active layers by name
+    fn set_keymap(&mut self, keymap: Keymap) {
+        // Keys held back for a combo are typed under the old keymap
+        if let Err(e) = self.flush_combo() {
+            warn!("Failed to flush a pending combo: {}", e);
+        }
+
+        let old = std::mem::replace(&mut self.keymap, keymap);
+        let relocate = |layer: usize| self.keymap.layer_index(&old.layers[layer].name);
+
+        self.active_layers = self
+            .active_layers
+            .iter()
+            .filter_map(|&l| relocate(l))
+            .collect();
+        self.layer_keys = self
+            .layer_keys
+            .iter()
+            .filter_map(|(&key, &l)| Some((key, relocate(l)?)))
+            .collect();
+        self.oneshot = self.oneshot.take().and_then(|o| {
+            Some(Oneshot {
+                layer: relocate(o.layer)?,
+                consumer: o.consumer,
+            })
+        });
+
+        // Flags keep their state by name, new ones start at their initial value
+        let flag = |name: &str| old.flags.iter().position(|(n, _)| n == name);
+        self.flags = self
+            .keymap
+            .flags
+            .iter()
+            .map(|(name, on)| flag(name).map_or(*on, |f| self.flags[f]))
+            .collect();
+        self.flag_keys = self
+            .flag_keys
+            .iter()
+            .filter_map(|(&key, &f)| {
+                let name = &old.flags[f].0;
+                Some((key, self.keymap.flags.iter().position(|(n, _)| n == name)?))
+            })
+            .collect();
+    }
+
+    /// The shared state as this keyboard sees it
+    fn shared(&self) -> SharedState {
+        SharedState {
+            enabled: self.enabled,
+            flags: self
+                .keymap
+                .flags
+                .iter()
+                .zip(&self.flags)
+                .map(|((name, _), &on)| (name.clone(), on))
+                .collect(),
+            app: self.app.clone(),
+        }
+    }
+
+    fn set_shared(&mut self, shared: &SharedState) {
+        if self.enabled != shared.enabled {
+            self.set_enabled(shared.enabled);
+        }
+        self.flags = self
+            .keymap
+            .flags
+            .iter()
+            .map(|(name, on)| shared.flags.get(name).copied().unwrap_or(*on))
+            .collect();
+        self.app.clone_from(&shared.app);
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.active_layers.clear();
+            self.oneshot = None;
+        }
+    }
+
+    fn is_toggle_pressed(&self, key: KeyCode) -> bool {
+        key == self.keymap.toggle_key
+            && self
+                .keymap
+                .toggle_mods
+                .iter()
+                .all(|m| self.held_keys.get(m).copied().unwrap_or(false))
+    }
+
+    /// Records an LED change reported by the device
+    fn set_led(&mut self, led: LedCode, on: bool) {
+        self.leds_reported = true;
+        self.leds.retain(|&l| l != led);
+        if on {
+            self.leds.push(led);
+        }
+    }
+
+    /// Guesses the LED a lock key toggles, for devices that don't report LED changes
+    fn update_led(&mut self, key: KeyCode) {
+        if self.leds_reported {
+            return;
+        }
+        let led = match key {
+            KeyCode::KEY_NUMLOCK => LedCode::LED_NUML,
+            KeyCode::KEY_CAPSLOCK => LedCode::LED_CAPSL,
+            KeyCode::KEY_SCROLLLOCK => LedCode::LED_SCROLLL,
+            _ => return,
+        };
+
+        if self.leds.contains(&led) {
+            self.leds.retain(|&l| l != led);
+        } else {
+            self.leds.push(led);
+        }
+    }
+
+    /// Whether a condition currently holds
+    fn condition(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::Led(led) => self.leds.contains(&led),
+            Condition::Flag(flag) => self.flags[flag],
+            Condition::App(app) => self
+                .app
+                .as_deref()
+                .is_some_and(|focused| glob_match(&self.keymap.apps[app], focused)),
+            // Consumed modifiers are still held as far as rules are concerned
+            Condition::Modifier(modifier) => modifier.keys().iter().any(|k| {
+                self.pressed.contains(k)
+                    || self.lapsed.contains(k)
+                    || self.consumed.values().any(|keys| keys.contains(k))
+            }),
+        }
+    }
+
+    fn remap_key(&self, key: KeyCode) -> Option<&MappingRule> {
+        if !self.enabled {
+            return None;
+        }
+
+        // Layers are searched from the most recently activated down to the base mappings
+        self.active_layers
+            .iter()
+            .rev()
+            .find_map(|&l| {
+                self.keymap.layers[l]
+                    .rules
+                    .iter()
+                    .find(|r| r.matches(key, self))
+            })
+            .or_else(|| self.keymap.rules.iter().find(|r| r.matches(key, self)))
+    }
+
+    /// Resolves the action for a key event, repeats & releases reuse the action of the press
+    fn key_action(&mut self, key: KeyCode, value: i32) -> Result<Option<Action>, Box<dyn Error>> {
+        match value {
+            1 => {
+                let (action, consume) = match self.remap_key(key) {
+                    Some(rule) => (Some(rule.action.clone()), rule.consume.clone()),
+                    None => (None, Vec::new()),
+                };
+                self.consume_modifiers(key, &consume)?;
+                self.key_actions.insert(key, action.clone());
+                Ok(action)
+            }
+            0 => Ok(self.key_actions.remove(&key).flatten()),
+            _ => Ok(self.key_actions.get(&key).cloned().flatten()),
+        }
+    }
+
+    /// Releases the modifiers a rule consumes while its key is held
+    fn consume_modifiers(
+        &mut self,
+        key: KeyCode,
+        modifiers: &[Modifier],
+    ) -> Result<(), Box<dyn Error>> {
+        let keys: Vec<KeyCode> = modifiers.iter().flat_map(|m| m.keys()).collect();
+        // Lapsed modifiers are up already
+        let mut released: Vec<KeyCode> = self
+            .lapsed
+            .iter()
+            .copied()
+            .filter(|k| keys.contains(k))
+            .collect();
+        self.lapsed.retain(|k| !keys.contains(k));
+
+        let pressed: Vec<KeyCode> = keys
+            .into_iter()
+            .filter(|k| self.pressed.contains(k))
+            .collect();
+        pressed.iter().try_for_each(|&k| self.emit_key(k, 0))?;
+        released.extend(pressed);
+        if !released.is_empty() {
+            self.consumed.insert(key, released);
+        }
+        Ok(())
+    }
+
+    /// Leaves modifiers consumed by a released key up until another key is pressed
+    fn lapse_modifiers(&mut self, key: KeyCode) {
+        if let Some(released) = self.consumed.remove(&key) {
+            self.lapsed.extend(released);
+        }
+    }
+
+    /// Presses lapsed modifiers that are still held again before `key`, unless its rule consumes
+    /// them too
+    fn restore_modifiers(&mut self, key: KeyCode) -> Result<(), Box<dyn Error>> {
+        let consumed: Vec<KeyCode> = self
+            .remap_key(key)
+            .into_iter()
+            .flat_map(|rule| &rule.consume)
+            .flat_map(|m| m.keys())
+            .collect();
+        let lapsed = std::mem::take(&mut self.lapsed);
+        for k in lapsed {
+            if consumed.contains(&k) {
+                self.lapsed.push(k);
+            } else if self.held_keys.get(&k).copied().unwrap_or(false) {
+                self.emit_key(k, 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Combos available in the active layers and base mappings, highest layer first
+    fn combo_rules(&self) -> Vec<&MappingRule> {
+        if !self.enabled {
+            return Vec::new();
+        }
+
+        self.active_layers
+            .iter()
+            .rev()
+            .flat_map(|&l| &self.keymap.layers[l].rules)
+            .chain(&self.keymap.rules)
+            .filter(|r| r.is_combo() && r.conditions_met(self))
+            .collect()
+    }
+
+    /// The most recently activated layer, the first searched for mappings
+    fn top_layer(&self) -> Option<&str> {
+        self.active_layers
+            .last()
+            .map(|&l| self.keymap.layers[l].name.as_str())
+    }
+
+    fn activate_layer(&mut self, layer: usize) {
+        self.active_layers.retain(|&l| l != layer);
+        self.active_layers.push(layer);
+    }
+
+    fn deactivate_layer(&mut self, layer: usize) {
+        self.active_layers.retain(|&l| l != layer);
+    }
+
+    /// Handles release of keys currently holding a layer, returns true if consumed
+    fn process_layer_key(&mut self, key: KeyCode, value: i32) -> bool {
+        let Some(&layer) = self.layer_keys.get(&key) else {
+            return false;
+        };
+
+        if value == 0 {
+            self.layer_keys.remove(&key);
+            self.deactivate_layer(layer);
+        }
+        true
+    }
+
+    /// Handles release of keys currently holding a flag, returns true if consumed
+    fn process_flag_key(&mut self, key: KeyCode, value: i32) -> bool {
+        let Some(&flag) = self.flag_keys.get(&key) else {
+            return false;
+        };
+
+        if value == 0 {
+            self.flag_keys.remove(&key);
+            self.flags[flag] = false;
+        }
+        true
+    }
+
+    fn process_flag_action(&mut self, key: KeyCode, action: Action) {
+        match action {
+            Action::FlagHold(flag) => {
+                self.flag_keys.insert(key, flag);
+                self.flags[flag] = true;
+            }
+            Action::FlagToggle(flag) => self.flags[flag] = !self.flags[flag],
+            _ => {}
+        }
+    }
+
+    fn process_layer_action(&mut self, key: KeyCode, action: Action) {
+        match action {
+            Action::LayerHold(layer) => {
+                self.layer_keys.insert(key, layer);
+                self.activate_layer(layer);
+            }
+            Action::LayerToggle(layer) => {
+                if self.active_layers.contains(&layer) {
+                    self.deactivate_layer(layer);
+                } else {
+                    self.activate_layer(layer);
+                }
+            }
+            Action::LayerOneshot(layer) => {
+                self.activate_layer(layer);
+                self.oneshot = Some(Oneshot {
+                    layer,
+                    consumer: None,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn emit_key(&mut self, key: KeyCode, value: i32) -> Result<(), Box<dyn Error>> {
+        if !self.own_buttons && mouse::BUTTONS.contains(&key) {
+            self.pointer.button(key, value)?;
+        } else {
+            self.virtual_kbd
+                .emit(&[InputEvent::new(EventType::KEY.0, key.0, value)])?;
+        }
+        if value == 0 {
+            self.pressed.remove(&key);
+        } else {
+            self.pressed.insert(key);
+        }
+        // Consumed modifiers that are released or pressed for real are no longer ours to restore
+        if value != 2 {
+            self.consumed
+                .values_mut()
+                .for_each(|keys| keys.retain(|&k| k != key));
+            self.lapsed.retain(|&k| k != key);
+        }
+        Ok(())
+    }
+
+    /// Drops pending combos, tap-holds & macros and releases every key down on the virtual device
+    fn release_all(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pending_combo = None;
+        self.tap_holds.clear();
+        self.macro_queue.clear();
+        self.text_released.clear();
+        self.consumed.clear();
+        self.lapsed.clear();
+        self.pointer.clear();
+
+        let pressed: Vec<KeyCode> = self.pressed.iter().copied().collect();
+        pressed
+            .into_iter()
+            .try_for_each(|key| self.emit_key(key, 0))
+    }
+
+    /// The keys of `keys` that aren't physically held
+    fn unheld(&self, keys: &[KeyCode]) -> Vec<KeyCode> {
+        keys.iter()
+            .copied()
+            .filter(|k| !self.held_keys.get(k).copied().unwrap_or(false))
+            .collect()
+    }
+
+    /// Presses or releases a chord, leaving modifiers that are physically held untouched
+    fn emit_chord(
+        &mut self,
+        mods: &[KeyCode],
+        key: KeyCode,
+        value: i32,
+    ) -> Result<(), Box<dyn Error>> {
+        let mods = self.unheld(mods);
+        match value {
+            1 => {
+                mods.iter().try_for_each(|&m| self.emit_key(m, 1))?;
+                self.emit_key(key, 1)
+            }
+            0 => {
+                self.emit_key(key, 0)?;
+                mods.iter().rev().try_for_each(|&m| self.emit_key(m, 0))
+            }
+            _ => self.emit_key(key, value),
+        }
+    }
+
+    /// Queues macro steps behind any macro still playing
+    fn play_macro(
+        &mut self,
+        steps: Vec<MacroStep>,
+        delay: Duration,
+        text: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.macro_queue.is_empty() {
+            self.macro_resume = Instant::now();
+        }
+
+        self.macro_queue
+            .extend(steps.into_iter().map(|step| (step, delay, text)));
+        self.advance_macro()
+    }
+
+    /// Plays queued macro steps until the next delay, without blocking
+    fn advance_macro(&mut self) -> Result<(), Box<dyn Error>> {
+        while self.macro_resume <= Instant::now() {
+            let Some((step, delay, text)) = self.macro_queue.pop_front() else {
+                break;
+            };
+
+            match step {
+                MacroStep::Tap(mods, key) => {
+                    // Held modifiers would change the text, elsewhere they stay down like in chords
+                    let mods = match text {
+                        true => {
+                            self.release_for_text()?;
+                            mods
+                        }
+                        false => self.unheld(&mods),
+                    };
+                    mods.iter().try_for_each(|&m| self.emit_key(m, 1))?;
+                    self.emit_key(key, 1)?;
+                    self.emit_key(key, 0)?;
+                    mods.iter().rev().try_for_each(|&m| self.emit_key(m, 0))?;
+                    self.macro_resume = Instant::now() + delay;
+                }
+                MacroStep::Delay(pause) => self.macro_resume = Instant::now() + pause,
+            }
+
+            if !self.macro_queue.front().is_some_and(|&(_, _, text)| text) {
+                self.restore_after_text()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases the modifiers down on the virtual device, to be restored once the text is typed
+    fn release_for_text(&mut self) -> Result<(), Box<dyn Error>> {
+        let down: Vec<KeyCode> = Modifier::ALL
+            .iter()
+            .flat_map(|m| m.keys())
+            .filter(|k| self.pressed.contains(k))
+            .collect();
+        for key in down {
+            self.emit_key(key, 0)?;
+            self.text_released.push(key);
+        }
+        Ok(())
+    }
+
+    /// Presses the modifiers released for text again, if the keys that pressed them are held
+    fn restore_after_text(&mut self) -> Result<(), Box<dyn Error>> {
+        let released = std::mem::take(&mut self.text_released);
+        for key in released {
+            let held_tap_hold = self
+                .tap_holds
+                .values()
+                .any(|state| matches!(state, TapHoldState::Held(hold) if *hold == key));
+            let held_action = self.key_actions.iter().any(|(&k, action)| match action {
+                None => k == key,
+                Some(Action::Key(output)) => *output == key,
+                Some(Action::Chord(mods, output)) => *output == key || mods.contains(&key),
+                _ => false,
+            });
+            if held_tap_hold || held_action {
+                self.emit_key(key, 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves a pending tap-hold key as held, pressing its hold key
+    fn resolve_hold(&mut self, key: KeyCode) -> Result<(), Box<dyn Error>> {
+        if let Some(TapHoldState::Pending { hold, .. }) = self.tap_holds.get(&key).copied() {
+            self.tap_holds.insert(key, TapHoldState::Held(hold));
+            self.emit_key(hold, 1)?;
+        }
+        Ok(())
+    }
+
+    /// The earliest time `tick` has work to do, if any
+    fn next_deadline(&self) -> Option<Instant> {
+        let tap_holds = self.tap_holds.values().filter_map(|state| match state {
+            TapHoldState::Pending { deadline, .. } => Some(*deadline),
+            TapHoldState::Held(_) => None,
+        });
+        let combo = self.pending_combo.as_ref().map(|c| c.deadline);
+        let macro_step = (!self.macro_queue.is_empty()).then_some(self.macro_resume);
+
+        tap_holds
+            .chain(combo)
+            .chain(macro_step)
+            .chain(self.pointer.deadline())
+            .min()
+    }
+
+    /// Resolves every pending tap-hold key whose timeout has expired
+    fn tick(&mut self) -> Result<(), Box<dyn Error>> {
+        let now = Instant::now();
+        let expired: Vec<KeyCode> = self
+            .tap_holds
+            .iter()
+            .filter(|(_, state)| {
+                matches!(state, TapHoldState::Pending { deadline, .. } if *deadline <= now)
+            })
+            .map(|(key, _)| *key)
+            .collect();
+
+        expired
+            .into_iter()
+            .try_for_each(|key| self.resolve_hold(key))?;
+
+        if self
+            .pending_combo
+            .as_ref()
+            .is_some_and(|c| c.deadline <= now)
+        {
+            self.flush_combo()?;
+        }
+
+        self.pointer.tick(&self.keymap.mouse)?;
+        self.advance_macro()
+    }
+
+    /// Passes the held back keys of an incomplete combo through as regular presses
+    fn flush_combo(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(pending) = self.pending_combo.take() {
+            for key in pending.keys {
+                let action = self.key_action(key, 1)?;
+                self.process_action(key, 1, action)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Holds back presses that may start or continue a combo, returns true if consumed
+    fn process_combo_press(&mut self, key: KeyCode) -> Result<bool, Box<dyn Error>> {
+        let mut keys = self
+            .pending_combo
+            .as_ref()
+            .map(|c| c.keys.clone())
+            .unwrap_or_default();
+        keys.push(key);
+
+        let rules = self.combo_rules();
+        let completed = rules
+            .iter()
+            .find(|r| r.from.len() == keys.len() && keys.iter().all(|k| r.from.contains(k)))
+            .map(|r| r.action.clone());
+        let partial = rules
+            .iter()
+            .any(|r| keys.iter().all(|k| r.from.contains(k)));
+
+        if let Some(action) = completed {
+            self.pending_combo = None;
+            let trigger = keys[0];
+            self.process_action(trigger, 1, Some(action.clone()))?;
+            self.active_combos.push(ActiveCombo {
+                trigger,
+                keys,
+                action,
+                released: false,
+            });
+            return Ok(true);
+        }
+
+        if partial {
+            let pending = self.pending_combo.get_or_insert(PendingCombo {
+                keys: Vec::new(),
+                deadline: Instant::now() + self.keymap.combo_timeout,
+            });
+            pending.keys.push(key);
+            return Ok(true);
+        }
+
+        if self.pending_combo.is_some() {
+            // The press can't extend the pending combo, but it may start a new one
+            self.flush_combo()?;
+            return self.process_combo_press(key);
+        }
+        Ok(false)
+    }
+
+    /// Handles events for keys of pending or triggered combos, returns true if consumed
+    fn process_combo_key(&mut self, key: KeyCode, value: i32) -> Result<bool, Box<dyn Error>> {
+        if let Some(pending) = &self.pending_combo {
+            if pending.keys.contains(&key) {
+                if value != 0 {
+                    return Ok(true);
+                }
+                self.flush_combo()?;
+                return Ok(false);
+            }
+        }
+
+        let Some(i) = self
+            .active_combos
+            .iter()
+            .position(|c| c.keys.contains(&key))
+        else {
+            return Ok(false);
+        };
+
+        let combo = &mut self.active_combos[i];
+        let (trigger, action, released) = (combo.trigger, combo.action.clone(), combo.released);
+
+        if value == 0 {
+            combo.keys.retain(|&k| k != key);
+            combo.released = true;
+            if combo.keys.is_empty() {
+                self.active_combos.remove(i);
+            }
+        }
+
+        if !released && !self.process_layer_key(trigger, value) {
+            self.process_action(trigger, value, Some(action))?;
+        }
+        Ok(true)
+    }
+
+    /// Handles events for keys currently acting as tap-hold keys, returns true if consumed
+    fn process_tap_hold(&mut self, key: KeyCode, value: i32) -> Result<bool, Box<dyn Error>> {
+        let Some(state) = self.tap_holds.get(&key).copied() else {
+            return Ok(false);
+        };
+
+        match (state, value) {
+            (TapHoldState::Pending { tap, .. }, 0) => {
+                self.tap_holds.remove(&key);
+                self.emit_key(tap, 1)?;
+                self.emit_key(tap, 0)?;
+            }
+            (TapHoldState::Held(hold), 0) => {
+                self.tap_holds.remove(&key);
+                self.emit_key(hold, 0)?;
+            }
+            (TapHoldState::Held(hold), _) => self.emit_key(hold, value)?,
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn process_event(&mut self, event: &InputEvent) -> Result<(), Box<dyn Error>> {
+        if let EventSummary::Key(_, key, value) = event.destructure() {
+            self.process_key(key, value)?;
+
+            if value == 0 {
+                self.lapse_modifiers(key);
+                if let Some(oneshot) = self.oneshot.take_if(|o| o.consumer == Some(key)) {
+                    self.deactivate_layer(oneshot.layer);
+                }
+            }
+        } else {
+            self.virtual_kbd.emit(&[*event])?;
+        }
+        Ok(())
+    }
+
+    fn process_key(&mut self, key: KeyCode, value: i32) -> Result<(), Box<dyn Error>> {
+        if value == 1 || value == 2 {
+            self.held_keys.insert(key, true);
+        } else if value == 0 {
+            self.held_keys.insert(key, false);
+        }
+        if value == 1 && !self.lapsed.is_empty() {
+            self.restore_modifiers(key)?;
+        }
+
+        if self.process_combo_key(key, value)?
+            || self.process_tap_hold(key, value)?
+            || self.process_layer_key(key, value)
+            || self.process_flag_key(key, value)
+        {
+            return Ok(());
+        }
+
+        if value == 1 {
+            // Pressing another key while a tap-hold key is undecided commits it to hold
+            let pending: Vec<KeyCode> = self.tap_holds.keys().copied().collect();
+            pending.into_iter().try_for_each(|k| self.resolve_hold(k))?;
+
+            self.update_led(key);
+        }
+
+        if value == 1 && self.is_toggle_pressed(key) {
+            self.set_enabled(!self.enabled);
+            return Ok(());
+        }
+
+        if value == 1 && self.process_combo_press(key)? {
+            return Ok(());
+        }
+
+        let action = self.key_action(key, value)?;
+        self.process_action(key, value, action)
+    }
+
+    fn process_action(
+        &mut self,
+        key: KeyCode,
+        value: i32,
+        action: Option<Action>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(
+            action @ (Action::LayerHold(_) | Action::LayerToggle(_) | Action::LayerOneshot(_)),
+        ) = action
+        {
+            if value == 1 {
+                self.process_layer_action(key, action);
+            }
+            return Ok(());
+        }
+        if let Some(action @ (Action::FlagHold(_) | Action::FlagToggle(_))) = action {
+            if value == 1 {
+                self.process_flag_action(key, action);
+            }
+            return Ok(());
+        }
+
+        if let Some(oneshot) = &mut self.oneshot {
+            if value == 1 && oneshot.consumer.is_none() {
+                oneshot.consumer = Some(key);
+            }
+        }
+
+        match action {
+            Some(Action::Key(remapped)) => self.emit_key(remapped, value)?,
+            Some(Action::Chord(mods, remapped)) => self.emit_chord(&mods, remapped, value)?,
+            Some(Action::Macro { steps, delay, text }) => {
+                if value == 1 {
+                    self.play_macro(steps, delay, text)?;
+                }
+            }
+            Some(Action::MouseMove(direction)) => match value {
+                1 => self.pointer.start(key, direction),
+                0 => self.pointer.stop(key),
+                _ => {}
+            },
+            // Key repeat keeps scrolling
+            Some(Action::Scroll(direction)) => {
+                if value != 0 {
+                    self.pointer.scroll(&self.keymap.mouse, direction)?;
+                }
+            }
+            Some(Action::TapHold { tap, hold, timeout }) if value == 1 => {
+                let deadline = Instant::now() + timeout;
+                self.tap_holds.insert(
+                    key,
+                    TapHoldState::Pending {
+                        tap,
+                        hold,
+                        deadline,
+                    },
+                );
+            }
+            // A lapsed modifier is up on the virtual device already
+            _ if value == 0 && self.lapsed.contains(&key) => self.lapsed.retain(|&k| k != key),
+            _ => self.emit_key(key, value)?,
+        }
+        Ok(())
+    }
+}
+
+/// Parses one `from = to` entry into the keys that trigger it, the modifiers it requires and
+/// its action
+///
+/// Modifiers followed by a single other key, like `shift+backspace`, require the modifiers to be
+/// held rather than forming a combo.
+fn parse_mapping(
+    from: &str,
+    to: &Target,
+    layer_names: &[String],
+    flag_names: &[String],
+    layout: &Layout,
+) -> Result<(Vec<KeyCode>, Vec<Modifier>, Action), String> {
+    let mut keys = from
+        .split('+')
+        .map(|k| parse_keycode(k.trim()).ok_or_else(|| format!("Invalid key: {}", k.trim())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let action = Action::parse(to, layer_names, flag_names, layout)?;
+
+    let mut modifiers = Vec::new();
+    if let Some((&key, mods)) = keys.split_last() {
+        let mods: Option<Vec<Modifier>> = mods.iter().map(|&k| Modifier::of(k)).collect();
+        if let (Some(mods), None, false) = (mods, Modifier::of(key), keys.len() == 1) {
+            modifiers = mods;
+            keys = vec![key];
+        }
+    }
+
+    // Tap-hold needs a single physical key to time
+    if keys.len() > 1 && matches!(action, Action::TapHold { .. }) {
+        return Err("Tap-hold can't be triggered by a combo".into());
+    }
+
+    Ok((keys, modifiers, action))
+}
+
+fn parse_rules(
+    context: &str,
+    mappings: &HashMap<String, Target>,
+    conditions: &[(Condition, bool)],
+    layer_names: &[String],
+    flag_names: &[String],
+    layout: &Layout,
+) -> Vec<MappingRule> {
+    let mut rules = Vec::new();
+
+    for (from, to) in mappings {
+        match parse_mapping(from, to, layer_names, flag_names, layout) {
+            Ok((keys, modifiers, action)) => {
+                let mut conditions = conditions.to_vec();
+                conditions.extend(
+                    modifiers
+                        .into_iter()
+                        .map(|m| (Condition::Modifier(m), true)),
+                );
+                let consume = match to {
+                    Target::Remap {
+                        modifiers: ModifierMode::Pass,
+                        ..
+                    } => Vec::new(),
+                    _ => Modifier::ALL
+                        .into_iter()
+                        .filter(|&m| conditions.contains(&(Condition::Modifier(m), true)))
+                        .collect(),
+                };
+
+                rules.push(MappingRule {
+                    source: format!("[{}] {} -> {}", context, from, to),
+                    from: keys,
+                    action,
+                    conditions,
+                    consume,
+                });
+            }
+            Err(e) => {
+                warn!(
+                    "Invalid mapping in [{}]: {} -> {} ({})",
+                    context, from, to, e
+                );
+            }
+        }
+    }
+
+    // Rules requiring modifiers are more specific & take precedence
+    rules.sort_by_key(|r| std::cmp::Reverse(r.conditions.len()));
+    rules
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("check") => {
+            let path = match args.get(1) {
+                Some(path) => path.clone(),
+                None => Config::find()?,
+            };
+            std::process::exit(if check::run(&path)? { 0 } else { 1 });
+        }
+        Some("daemon") => {
+            let pid_file = match args.get(1..) {
+                Some([flag, path]) if flag == "--pid-file" => Some(PathBuf::from(path)),
+                Some([]) => None,
+                _ => return Err("Usage: rk daemon [--pid-file PATH]".into()),
+            };
+            run(true, pid_file)
+        }
+        Some(other) => Err(format!("Unknown command: {}", other).into()),
+        None => run(false, None),
+    }
+}
+
+/// Remaps keyboards until SIGINT/SIGTERM, `daemon` reporting readiness to systemd
+///
+/// The PID file is only written once the keyboards are grabbed, so a second instance that fails
+/// to start leaves the first one's in place
+fn run(daemon: bool, pid_file: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let config_path = Config::find()?;
+    let mut config = Config::load(&config_path)?;
+    info!("Loaded config: {}", config_path);
+
+    let mut watcher = ConfigWatcher::new(&config_path)?;
+    let mut hotplug = DeviceWatcher::new()?;
+    let mut shutdown = ShutdownWatcher::new()?;
+    let mut control = ControlServer::new(&config.control)?;
+    let mut context = ContextWatcher::new(&config.context)?;
+    let mut keyboards = find_keyboards(&config.devices)?
+        .into_iter()
+        .map(|(path, dev)| Keyboard::new(dev, path, &config))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut shared = SharedState::new(&config);
+    shared.sync(&mut keyboards, None);
+
+    let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+    let readable = EpollEvent::new(EpollFlags::EPOLLIN, 0);
+    for fd in watcher.fds() {
+        epoll.add(fd, readable)?;
+    }
+    epoll.add(&hotplug, readable)?;
+    epoll.add(&shutdown, readable)?;
+    if let Some(control) = &control {
+        epoll.add(control, readable)?;
+    }
+    epoll.add(&context, readable)?;
+    for kb in &keyboards {
+        epoll.add(&kb.device, readable)?;
+    }
+
+    if keyboards.is_empty() {
+        info!("No keyboards found, waiting for one to be connected");
+    }
+    info!("Press {} to toggle remapping", config.toggle);
+    let _pid_file = pid_file.map(PidFile::create).transpose()?;
+    if daemon {
+        daemon::notify("READY=1");
+    }
+
+    let mut events = [EpollEvent::empty(); 16];
+    // The status stream follows the keyboard typed on last
+    let mut last_used: Option<PathBuf> = None;
+    let mut feedback = Feedback::default();
+    loop {
+        // Sleep until a device or watcher has input, or the next tap-hold/combo/macro/hotplug/
+        // control deadline
+        let timeout = keyboards
+            .iter()
+            .filter_map(|kb| kb.remapper.next_deadline())
+            .chain(feedback.deadline())
+            .chain(hotplug.deadline())
+            .chain(context.deadline())
+            .chain(control.as_ref().and_then(ControlServer::deadline))
+            .min()
+            .map_or(EpollTimeout::NONE, |deadline| {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                // Round up so the deadline has passed when we wake
+                EpollTimeout::try_from(wait.as_micros().div_ceil(1000)).unwrap_or(EpollTimeout::MAX)
+            });
+        match epoll.wait(&mut events, timeout) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if shutdown.requested() {
+            feedback.clear(&mut keyboards);
+            for kb in &mut keyboards {
+                kb.remapper.release_all()?;
+                kb.device.ungrab()?;
+            }
+            // Dropping the keyboards destroys their virtual devices
+            drop(keyboards);
+            if daemon {
+                daemon::notify("STOPPING=1");
+            }
+            info!("Exiting");
+            return Ok(());
+        }
+
+        let enabled = shared.enabled;
+        // Layers are per keyboard, so changes are too, whichever keyboard was used last
+        let layers: Vec<(PathBuf, Option<String>)> = keyboards
+            .iter()
+            .map(|kb| (kb.path.clone(), kb.remapper.top_layer().map(String::from)))
+            .collect();
+        let mut removed = Vec::new();
+
+        // Before reading keys, so they're mapped for the app they're typed into
+        if let Some(app) = context.changes() {
+            shared.app = app;
+            shared.sync(&mut keyboards, None);
+        }
+
+        for i in 0..keyboards.len() {
+            let kb = &mut keyboards[i];
+
+            // Collected first, handling an event needs the whole keyboard
+            let events = kb.device.fetch_events().map(Iterator::collect::<Vec<_>>);
+            match events {
+                Ok(events) => {
+                    events.iter().try_for_each(|e| kb.process_event(e))?;
+                    last_used = Some(kb.path.clone());
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => removed.push(kb.path.clone()),
+            }
+            kb.remapper.tick()?;
+
+            // Toggling or flipping a flag on any keyboard applies to all of them
+            if kb.remapper.shared() != shared {
+                shared.sync(&mut keyboards, Some(i));
+            }
+        }
+
+        for change in hotplug.changes() {
+            match change {
+                DeviceChange::Added(path) if keyboards.iter().all(|kb| kb.path != path) => {
+                    if let Some(dev) = open_keyboard(&path, &config.devices) {
+                        match Keyboard::new(dev, path, &config) {
+                            Ok(mut kb) => {
+                                epoll.add(&kb.device, readable)?;
+                                kb.remapper.set_shared(&shared);
+                                keyboards.push(kb);
+                            }
+                            Err(e) => warn!("Failed to attach keyboard: {}", e),
+                        }
+                    }
+                }
+                DeviceChange::Added(_) => {}
+                DeviceChange::Removed(path) => removed.push(path),
+            }
+        }
+
+        // Dropping a keyboard closes its fd, leaving epoll, and destroys its virtual device,
+        // which releases its held keys
+        keyboards.retain(|kb| {
+            let keep = !removed.contains(&kb.path);
+            if !keep {
+                info!("Removed: {} ({:?})", kb.name(), kb.path);
+            }
+            keep
+        });
+
+        let mut reload = watcher.reload_requested();
+        let mut reload_clients = Vec::new();
+        let requests = control
+            .as_mut()
+            .map(ControlServer::requests)
+            .unwrap_or_default();
+        for (stream, request) in requests {
+            match request {
+                Ok(Request::Watch) => {
+                    if let Some(control) = &mut control {
+                        control.subscribe(stream);
+                    }
+                }
+                Ok(Request::Reload) => {
+                    reload = true;
+                    reload_clients.push(stream);
+                }
+                Ok(request) => {
+                    let result = control::handle(request, &mut keyboards, &mut shared, &config);
+                    shared.sync(&mut keyboards, None);
+                    if let Some(control) = &mut control {
+                        control.reply(stream, result);
+                    }
+                }
+                Err(e) => {
+                    if let Some(control) = &mut control {
+                        control.reply(stream, Err(e));
+                    }
+                }
+            }
+        }
+
+        if reload {
+            if daemon {
+                daemon::notify("RELOADING=1");
+            }
+            let reloaded = Config::load(&config_path)
+                .and_then(|c| device::reload(&mut keyboards, &c).map(|()| c));
+            let result = match reloaded {
+                Ok(c) => {
+                    config = c;
+                    shared.reload(&config);
+                    shared.sync(&mut keyboards, None);
+                    info!("Reloaded config: {}", config_path);
+                    Ok(String::new())
+                }
+                Err(e) => {
+                    error!("Keeping previous config, reload failed: {}", e);
+                    Err(e.to_string())
+                }
+            };
+            if daemon {
+                daemon::notify("READY=1");
+            }
+            if let Some(control) = &mut control {
+                for stream in reload_clients {
+                    control.reply(stream, result.clone());
+                }
+            }
+        }
+
+        let status = control::status(&keyboards, &shared, last_used.as_deref());
+        let layer_changed = layers.iter().find_map(|(path, layer)| {
+            let kb = keyboards.iter().find(|kb| &kb.path == path)?;
+            (kb.remapper.top_layer() != layer.as_deref()).then_some(path)
+        });
+        if shared.enabled != enabled {
+            feedback.send(&config, Event::Toggle, &status, &mut keyboards);
+        } else if let Some(path) = layer_changed {
+            let status = control::status(&keyboards, &shared, Some(path));
+            feedback.send(&config, Event::Layer, &status, &mut keyboards);
+        }
+        feedback.tick(&mut keyboards);
+        feedback.indicate(&config.indicator, &mut keyboards);
+
+        if let Some(control) = &mut control {
+            control.publish(&status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Records the key events a remapper sends
+    #[derive(Clone, Default)]
+    struct Recorder(Rc<RefCell<Vec<(KeyCode, i32)>>>);
+
+    impl Output for Recorder {
+        fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+            for event in events {
+                if let EventSummary::Key(_, key, value) = event.destructure() {
+                    self.0.borrow_mut().push((key, value));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Recorder {
+        /// The events sent since the last call
+        fn take(&self) -> Vec<(KeyCode, i32)> {
+            self.0.take()
+        }
+    }
+
+    /// An enabled remapper for `config`, with no lock LEDs on
+    fn remapper(config: &str) -> (KeyRemapper, Recorder) {
+        remapper_with_leds(config, Vec::new())
+    }
+
+    fn remapper_with_leds(config: &str, leds: Vec<LedCode>) -> (KeyRemapper, Recorder) {
+        let config: Config = toml::from_str(config).unwrap();
+        let keymap = Keymap::new(&config, None).unwrap();
+        let output = Recorder::default();
+        let mut remapper =
+            KeyRemapper::with_output(Box::new(output.clone()), leds, keymap).unwrap();
+        remapper.set_enabled(true);
+        (remapper, output)
+    }
+
+    fn key(remapper: &mut KeyRemapper, key: KeyCode, value: i32) {
+        remapper
+            .process_event(&InputEvent::new(EventType::KEY.0, key.0, value))
+            .unwrap();
+    }
+
+    const TAP_HOLD: &str = r#"
+toggle = "ctrl+alt+f12"
+[mappings.default]
+capslock = { tap = "esc", hold = "leftctrl", timeout_ms = 10000 }
+tab = { tap = "tab", hold = "leftmeta", timeout_ms = 0 }
+"#;
+
+    #[test]
+    fn tap_hold_taps_when_released_in_time() {
+        let (mut remapper, output) = remapper(TAP_HOLD);
+        key(&mut remapper, KeyCode::KEY_CAPSLOCK, 1);
+        assert_eq!(output.take(), []);
+        key(&mut remapper, KeyCode::KEY_CAPSLOCK, 0);
+        assert_eq!(
+            output.take(),
+            [(KeyCode::KEY_ESC, 1), (KeyCode::KEY_ESC, 0)]
+        );
+    }
+
+    #[test]
+    fn tap_hold_holds_on_timeout() {
+        let (mut remapper, output) = remapper(TAP_HOLD);
+        key(&mut remapper, KeyCode::KEY_TAB, 1);
+        assert!(remapper.next_deadline().is_some());
+        remapper.tick().unwrap();
+        assert_eq!(output.take(), [(KeyCode::KEY_LEFTMETA, 1)]);
+
+        key(&mut remapper, KeyCode::KEY_TAB, 2);
+        key(&mut remapper, KeyCode::KEY_TAB, 0);
+        assert_eq!(
+            output.take(),
+            [(KeyCode::KEY_LEFTMETA, 2), (KeyCode::KEY_LEFTMETA, 0)]
+        );
+    }
+
+    #[test]
+    fn tap_hold_holds_when_another_key_is_pressed() {
+        let (mut remapper, output) = remapper(TAP_HOLD);
+        key(&mut remapper, KeyCode::KEY_CAPSLOCK, 1);
+        key(&mut remapper, KeyCode::KEY_C, 1);
+        key(&mut remapper, KeyCode::KEY_C, 0);
+        key(&mut remapper, KeyCode::KEY_CAPSLOCK, 0);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_C, 1),
+                (KeyCode::KEY_C, 0),
+                (KeyCode::KEY_LEFTCTRL, 0),
+            ]
+        );
+    }
+
+    fn mapping(from: &str, to: Target) -> Result<(Vec<KeyCode>, Vec<Modifier>, Action), String> {
+        let layout = Layout::new(&TextConfig::default()).unwrap();
+        parse_mapping(from, &to, &["nav".into()], &[], &layout)
+    }
+
+    fn target(value: &str) -> Result<Target, String> {
+        toml::from_str::<HashMap<String, Target>>(&format!("t = {}", value))
+            .map(|mut targets| targets.remove("t").unwrap())
+            .map_err(|e| e.message().to_string())
+    }
+
+    #[test]
+    fn modifiers_before_a_key_are_conditions() {
+        let (keys, modifiers, action) =
+            mapping("shift+backspace", Target::Key("delete".into())).unwrap();
+        assert_eq!(keys, [KeyCode::KEY_BACKSPACE]);
+        assert_eq!(modifiers, [Modifier::Shift]);
+        assert!(action == Action::Key(KeyCode::KEY_DELETE));
+
+        let (keys, modifiers, _) = mapping("ctrl+rightalt+j", Target::Key("left".into())).unwrap();
+        assert_eq!(keys, [KeyCode::KEY_J]);
+        assert_eq!(modifiers, [Modifier::Ctrl, Modifier::Alt]);
+    }
+
+    #[test]
+    fn consumed_modifiers_come_back_for_the_next_key_only() {
+        let config = r#"
+toggle = "ctrl+alt+f12"
+[mappings.default]
+"alt+h" = "left"
+"#;
+        let (mut remapper, output) = remapper(config);
+        key(&mut remapper, KeyCode::KEY_LEFTALT, 1);
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        key(&mut remapper, KeyCode::KEY_H, 0);
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        key(&mut remapper, KeyCode::KEY_H, 0);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_LEFTALT, 1),
+                (KeyCode::KEY_LEFTALT, 0),
+                (KeyCode::KEY_LEFT, 1),
+                (KeyCode::KEY_LEFT, 0),
+                (KeyCode::KEY_LEFT, 1),
+                (KeyCode::KEY_LEFT, 0),
+            ]
+        );
+
+        // Letting go isn't a lone alt tap
+        key(&mut remapper, KeyCode::KEY_LEFTALT, 0);
+        assert_eq!(output.take(), []);
+
+        key(&mut remapper, KeyCode::KEY_LEFTALT, 1);
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        key(&mut remapper, KeyCode::KEY_H, 0);
+        key(&mut remapper, KeyCode::KEY_TAB, 1);
+        key(&mut remapper, KeyCode::KEY_TAB, 0);
+        key(&mut remapper, KeyCode::KEY_LEFTALT, 0);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_LEFTALT, 1),
+                (KeyCode::KEY_LEFTALT, 0),
+                (KeyCode::KEY_LEFT, 1),
+                (KeyCode::KEY_LEFT, 0),
+                (KeyCode::KEY_LEFTALT, 1),
+                (KeyCode::KEY_TAB, 1),
+                (KeyCode::KEY_TAB, 0),
+                (KeyCode::KEY_LEFTALT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_keys_together_are_a_combo() {
+        let (keys, modifiers, _) = mapping("j+k", Target::Key("esc".into())).unwrap();
+        assert_eq!(keys, [KeyCode::KEY_J, KeyCode::KEY_K]);
+        assert!(modifiers.is_empty());
+
+        // A non-modifier before the last key makes all of them combo keys
+        let (keys, modifiers, _) = mapping("ctrl+j+k", Target::Key("esc".into())).unwrap();
+        assert_eq!(
+            keys,
+            [KeyCode::KEY_LEFTCTRL, KeyCode::KEY_J, KeyCode::KEY_K]
+        );
+        assert!(modifiers.is_empty());
+
+        // So does a modifier as the last key
+        let (keys, modifiers, _) =
+            mapping("leftshift+rightshift", Target::Key("capslock".into())).unwrap();
+        assert_eq!(keys, [KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_RIGHTSHIFT]);
+        assert!(modifiers.is_empty());
+    }
+
+    #[test]
+    fn a_single_modifier_is_a_key() {
+        let (keys, modifiers, _) = mapping("leftctrl", Target::Key("esc".into())).unwrap();
+        assert_eq!(keys, [KeyCode::KEY_LEFTCTRL]);
+        assert!(modifiers.is_empty());
+    }
+
+    #[test]
+    fn tap_hold_needs_a_single_key() {
+        let tap_hold = || Target::TapHold {
+            tap: "esc".into(),
+            hold: "leftctrl".into(),
+            timeout_ms: None,
+        };
+        assert!(mapping("shift+capslock", tap_hold()).is_ok());
+        assert_eq!(
+            mapping("j+k", tap_hold()).err().as_deref(),
+            Some("Tap-hold can't be triggered by a combo")
+        );
+    }
+
+    #[test]
+    fn invalid_keys_are_named() {
+        assert_eq!(
+            mapping("shift+nope", Target::Key("esc".into()))
+                .err()
+                .as_deref(),
+            Some("Invalid key: nope")
+        );
+    }
+
+    #[test]
+    fn targets_are_told_apart_by_their_fields() {
+        assert!(matches!(target(r#""esc""#), Ok(Target::Key(key)) if key == "esc"));
+        assert!(matches!(
+            target(r#"{ tap = "esc", hold = "leftctrl", timeout_ms = 150 }"#),
+            Ok(Target::TapHold {
+                timeout_ms: Some(150),
+                ..
+            })
+        ));
+        assert!(matches!(
+            target(r#"{ key = "delete", modifiers = "pass" }"#),
+            Ok(Target::Remap {
+                modifiers: ModifierMode::Pass,
+                ..
+            })
+        ));
+        assert!(matches!(
+            target(r#"{ scroll = "up" }"#),
+            Ok(Target::Scroll { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_targets_say_why() {
+        let error = |value| target(value).err().unwrap_or_default();
+        assert_eq!(error(r#"{ tap = "esc" }"#), "tap needs a hold");
+        assert_eq!(
+            error(r#"{ text = "hi", timeout_ms = 10 }"#),
+            "timeout_ms only applies to tap & hold"
+        );
+        assert!(error(r#"{ text = "hi", scroll = "up" }"#).starts_with("expected one of"));
+        assert!(error(r#"{ txet = "hi" }"#).contains("unknown field `txet`"));
+    }
+
+    const LAYERS: &str = r#"
+toggle = "ctrl+alt+f12"
+[mappings.default]
+rightalt = "layer_hold(nav)"
+f13 = "layer_oneshot(nav)"
+f14 = "layer_toggle(nav)"
+
+[layers.nav]
+h = "left"
+"#;
+
+    #[test]
+    fn layer_hold_applies_while_held() {
+        let (mut remapper, output) = remapper(LAYERS);
+        key(&mut remapper, KeyCode::KEY_RIGHTALT, 1);
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        key(&mut remapper, KeyCode::KEY_H, 0);
+        key(&mut remapper, KeyCode::KEY_RIGHTALT, 0);
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_LEFT, 1),
+                (KeyCode::KEY_LEFT, 0),
+                (KeyCode::KEY_H, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn layer_toggle_stays_until_toggled_again() {
+        let (mut remapper, output) = remapper(LAYERS);
+        key(&mut remapper, KeyCode::KEY_F14, 1);
+        key(&mut remapper, KeyCode::KEY_F14, 0);
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        key(&mut remapper, KeyCode::KEY_H, 0);
+        assert_eq!(remapper.top_layer(), Some("nav"));
+
+        key(&mut remapper, KeyCode::KEY_F14, 1);
+        key(&mut remapper, KeyCode::KEY_F14, 0);
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_LEFT, 1),
+                (KeyCode::KEY_LEFT, 0),
+                (KeyCode::KEY_H, 1),
+            ]
+        );
+        assert_eq!(remapper.top_layer(), None);
+    }
+
+    #[test]
+    fn oneshot_layer_applies_to_the_next_key_only() {
+        let (mut remapper, output) = remapper(LAYERS);
+        key(&mut remapper, KeyCode::KEY_F13, 1);
+        key(&mut remapper, KeyCode::KEY_F13, 0);
+        assert_eq!(output.take(), []);
+
+        // The layer stays while the consuming key is held, so its repeats are mapped too
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        key(&mut remapper, KeyCode::KEY_H, 2);
+        assert_eq!(remapper.top_layer(), Some("nav"));
+        key(&mut remapper, KeyCode::KEY_H, 0);
+        assert_eq!(remapper.top_layer(), None);
+
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_LEFT, 1),
+                (KeyCode::KEY_LEFT, 2),
+                (KeyCode::KEY_LEFT, 0),
+                (KeyCode::KEY_H, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_are_released_as_what_they_were_pressed_as() {
+        // The layer ends before the key is released
+        let (mut remapper, output) = remapper(LAYERS);
+        key(&mut remapper, KeyCode::KEY_RIGHTALT, 1);
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        key(&mut remapper, KeyCode::KEY_RIGHTALT, 0);
+        key(&mut remapper, KeyCode::KEY_H, 2);
+        key(&mut remapper, KeyCode::KEY_H, 0);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_LEFT, 1),
+                (KeyCode::KEY_LEFT, 2),
+                (KeyCode::KEY_LEFT, 0),
+            ]
+        );
+
+        // rk is turned off mid-press
+        remapper.set_enabled(true);
+        key(&mut remapper, KeyCode::KEY_RIGHTALT, 1);
+        key(&mut remapper, KeyCode::KEY_H, 1);
+        remapper.set_enabled(false);
+        key(&mut remapper, KeyCode::KEY_H, 0);
+        assert_eq!(
+            output.take(),
+            [(KeyCode::KEY_LEFT, 1), (KeyCode::KEY_LEFT, 0)]
+        );
+
+        // NumLock changes mid-press
+        let config = r#"
+toggle = "ctrl+alt+f12"
+[mappings.numlock_off]
+kp1 = "end"
+"#;
+        let (mut remapper, output) = remapper_with_leds(config, Vec::new());
+        key(&mut remapper, KeyCode::KEY_KP1, 1);
+        remapper.set_led(LedCode::LED_NUML, true);
+        key(&mut remapper, KeyCode::KEY_KP1, 0);
+        key(&mut remapper, KeyCode::KEY_KP1, 1);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_END, 1),
+                (KeyCode::KEY_END, 0),
+                (KeyCode::KEY_KP1, 1),
+            ]
+        );
+    }
+
+    /// Combos with `timeout_ms` to complete them
+    fn combos(timeout_ms: u64) -> String {
+        format!(
+            r#"
+toggle = "ctrl+alt+f12"
+combo_timeout_ms = {}
+[mappings.default]
+"j+k" = "esc"
+"#,
+            timeout_ms
+        )
+    }
+
+    #[test]
+    fn combo_triggers_when_pressed_together() {
+        let (mut remapper, output) = remapper(&combos(10000));
+        key(&mut remapper, KeyCode::KEY_J, 1);
+        key(&mut remapper, KeyCode::KEY_K, 1);
+        key(&mut remapper, KeyCode::KEY_J, 0);
+        key(&mut remapper, KeyCode::KEY_K, 0);
+        assert_eq!(
+            output.take(),
+            [(KeyCode::KEY_ESC, 1), (KeyCode::KEY_ESC, 0)]
+        );
+    }
+
+    #[test]
+    fn combo_key_is_typed_on_timeout() {
+        let (mut remapper, output) = remapper(&combos(0));
+        key(&mut remapper, KeyCode::KEY_J, 1);
+        assert_eq!(output.take(), []);
+        remapper.tick().unwrap();
+        key(&mut remapper, KeyCode::KEY_J, 0);
+        assert_eq!(output.take(), [(KeyCode::KEY_J, 1), (KeyCode::KEY_J, 0)]);
+    }
+
+    #[test]
+    fn combo_key_is_typed_when_released_early() {
+        let (mut remapper, output) = remapper(&combos(10000));
+        key(&mut remapper, KeyCode::KEY_J, 1);
+        key(&mut remapper, KeyCode::KEY_J, 0);
+        key(&mut remapper, KeyCode::KEY_A, 1);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_J, 1),
+                (KeyCode::KEY_J, 0),
+                (KeyCode::KEY_A, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn macros_leave_held_modifiers_down() {
+        let config = r#"
+toggle = "ctrl+alt+f12"
+[mappings.default]
+f5 = { macro = ["ctrl+a", "shift+b"], delay_ms = 0 }
+"#;
+        let (mut remapper, output) = remapper(config);
+        key(&mut remapper, KeyCode::KEY_LEFTCTRL, 1);
+        key(&mut remapper, KeyCode::KEY_F5, 1);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_A, 1),
+                (KeyCode::KEY_A, 0),
+                (KeyCode::KEY_LEFTSHIFT, 1),
+                (KeyCode::KEY_B, 1),
+                (KeyCode::KEY_B, 0),
+                (KeyCode::KEY_LEFTSHIFT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn text_is_typed_with_held_modifiers_released() {
+        let config = r#"
+toggle = "ctrl+alt+f12"
+[mappings.default]
+f6 = { text = "aB", delay_ms = 0 }
+"#;
+        let (mut remapper, output) = remapper(config);
+        key(&mut remapper, KeyCode::KEY_LEFTSHIFT, 1);
+        key(&mut remapper, KeyCode::KEY_F6, 1);
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_LEFTSHIFT, 1),
+                (KeyCode::KEY_LEFTSHIFT, 0),
+                (KeyCode::KEY_A, 1),
+                (KeyCode::KEY_A, 0),
+                (KeyCode::KEY_LEFTSHIFT, 1),
+                (KeyCode::KEY_B, 1),
+                (KeyCode::KEY_B, 0),
+                (KeyCode::KEY_LEFTSHIFT, 0),
+                (KeyCode::KEY_LEFTSHIFT, 1),
+            ]
+        );
+
+        // Modifiers let go of meanwhile stay released
+        key(&mut remapper, KeyCode::KEY_LEFTSHIFT, 0);
+        key(&mut remapper, KeyCode::KEY_F6, 0);
+        output.take();
+        key(&mut remapper, KeyCode::KEY_LEFTCTRL, 1);
+        remapper.release_for_text().unwrap();
+        key(&mut remapper, KeyCode::KEY_LEFTCTRL, 0);
+        remapper.restore_after_text().unwrap();
+        assert_eq!(
+            output.take(),
+            [
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_LEFTCTRL, 0),
+                (KeyCode::KEY_LEFTCTRL, 0),
+            ]
+        );
+    }
 
-        sleep(Duration::from_micros(100));
+    #[test]
+    fn buttons_of_pointing_devices_stay_on_their_device() {
+        let (mut remapper, output) = remapper(TAP_HOLD);
+        remapper.own_buttons = true;
+        key(&mut remapper, KeyCode::BTN_LEFT, 1);
+        key(&mut remapper, KeyCode::BTN_LEFT, 0);
+        assert_eq!(
+            output.take(),
+            [(KeyCode::BTN_LEFT, 1), (KeyCode::BTN_LEFT, 0)]
+        );
     }
 }
@@ -0,0 +1,259 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, EventType, InputEvent, KeyCode, RelativeAxisCode};
+
+use crate::VIRTUAL_POINTER_NAME;
+
+/// Mouse buttons keys can be mapped to, e.g. `btn_left`
+pub const BUTTONS: [KeyCode; 7] = [
+    KeyCode::BTN_LEFT,
+    KeyCode::BTN_RIGHT,
+    KeyCode::BTN_MIDDLE,
+    KeyCode::BTN_SIDE,
+    KeyCode::BTN_EXTRA,
+    KeyCode::BTN_FORWARD,
+    KeyCode::BTN_BACK,
+];
+
+/// Hi-res wheel units in one wheel notch, as defined by the kernel
+const NOTCH: i32 = 120;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct MouseConfig {
+    /// Pixels the pointer moves per interval when a key is first pressed
+    speed: f64,
+    /// Pixels per interval once fully accelerated
+    max_speed: f64,
+    /// Time from `speed` to `max_speed`
+    acceleration_ms: u64,
+    interval_ms: u64,
+    /// Hi-res wheel units per scroll, 120 is one notch
+    scroll_step: i32,
+}
+
+impl Default for MouseConfig {
+    fn default() -> Self {
+        Self {
+            speed: 2.0,
+            max_speed: 20.0,
+            acceleration_ms: 600,
+            interval_ms: 10,
+            scroll_step: NOTCH,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "up" => Ok(Direction::Up),
+            "down" => Ok(Direction::Down),
+            "left" => Ok(Direction::Left),
+            "right" => Ok(Direction::Right),
+            _ => Err(format!(
+                "Unknown direction: {} (expected up, down, left or right)",
+                s
+            )),
+        }
+    }
+
+    /// Screen coordinates, y grows downwards
+    fn delta(self) -> (i32, i32) {
+        match self {
+            Direction::Up => (0, -1),
+            Direction::Down => (0, 1),
+            Direction::Left => (-1, 0),
+            Direction::Right => (1, 0),
+        }
+    }
+}
+
+/// Relative axes the virtual pointer needs for motion & scrolling
+fn axes() -> AttributeSet<RelativeAxisCode> {
+    [
+        RelativeAxisCode::REL_X,
+        RelativeAxisCode::REL_Y,
+        RelativeAxisCode::REL_WHEEL,
+        RelativeAxisCode::REL_HWHEEL,
+        RelativeAxisCode::REL_WHEEL_HI_RES,
+        RelativeAxisCode::REL_HWHEEL_HI_RES,
+    ]
+    .into_iter()
+    .collect()
+}
+
+fn rel(axis: RelativeAxisCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::RELATIVE.0, axis.0, value)
+}
+
+/// Pointer motion while keys are held, scrolling & buttons, on a virtual pointer
+///
+/// The pointer is its own device, created once a keymap uses it, as udev & libinput would take
+/// a keyboard with buttons & axes for a mouse, e.g. disabling the touchpad while it's connected.
+#[derive(Default)]
+pub struct Pointer {
+    device: Option<VirtualDevice>,
+    /// Held keys moving the pointer
+    moves: HashMap<KeyCode, Direction>,
+    /// When the pointer started moving, for acceleration
+    started: Option<Instant>,
+    next_move: Option<Instant>,
+    /// Hi-res wheel units not adding up to a notch yet, horizontal & vertical
+    wheel: (i32, i32),
+}
+
+impl Pointer {
+    pub fn start(&mut self, key: KeyCode, direction: Direction) {
+        if self.moves.is_empty() {
+            let now = Instant::now();
+            self.started = Some(now);
+            self.next_move = Some(now);
+        }
+        self.moves.insert(key, direction);
+    }
+
+    pub fn stop(&mut self, key: KeyCode) {
+        self.moves.remove(&key);
+        if self.moves.is_empty() {
+            self.started = None;
+            self.next_move = None;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.moves.clear();
+        self.started = None;
+        self.next_move = None;
+    }
+
+    /// The virtual pointer, created if it isn't yet
+    fn device(&mut self) -> Result<&mut VirtualDevice, Box<dyn Error>> {
+        if self.device.is_none() {
+            let buttons: AttributeSet<KeyCode> = BUTTONS.into_iter().collect();
+            #[allow(deprecated)]
+            let device = VirtualDeviceBuilder::new()?
+                .name(VIRTUAL_POINTER_NAME)
+                .with_keys(&buttons)?
+                .with_relative_axes(&axes())?
+                .build()?;
+            self.device = Some(device);
+        }
+        Ok(self.device.as_mut().unwrap())
+    }
+
+    /// Creates the virtual pointer ahead of its first use
+    pub fn open(&mut self) -> Result<(), Box<dyn Error>> {
+        self.device().map(|_| ())
+    }
+
+    /// Presses or releases a mouse button
+    pub fn button(&mut self, button: KeyCode, value: i32) -> Result<(), Box<dyn Error>> {
+        self.device()?
+            .emit(&[InputEvent::new(EventType::KEY.0, button.0, value)])?;
+        Ok(())
+    }
+
+    /// When the pointer moves next, if it's moving
+    pub fn deadline(&self) -> Option<Instant> {
+        self.next_move
+    }
+
+    /// Moves the pointer if it's due, accelerating the longer it's been moving
+    pub fn tick(&mut self, config: &MouseConfig) -> Result<(), Box<dyn Error>> {
+        let now = Instant::now();
+        let (Some(started), Some(next_move)) = (self.started, self.next_move) else {
+            return Ok(());
+        };
+        if next_move > now {
+            return Ok(());
+        }
+
+        let interval = Duration::from_millis(config.interval_ms.max(1));
+        // Skip intervals that were missed rather than catching up in one jump
+        self.next_move = Some((next_move + interval).max(now));
+
+        // Opposite keys cancel out, keys for the same direction don't add up
+        let (mut dx, mut dy) = (0, 0);
+        for direction in [
+            Direction::Up,
+            Direction::Down,
+            Direction::Left,
+            Direction::Right,
+        ] {
+            if self.moves.values().any(|&d| d == direction) {
+                let (x, y) = direction.delta();
+                dx += x;
+                dy += y;
+            }
+        }
+
+        let progress = match config.acceleration_ms {
+            0 => 1.0,
+            ms => (now.duration_since(started).as_secs_f64() * 1000.0 / ms as f64).min(1.0),
+        };
+        let speed = (config.speed + (config.max_speed - config.speed) * progress).round() as i32;
+
+        let events: Vec<InputEvent> =
+            [(RelativeAxisCode::REL_X, dx), (RelativeAxisCode::REL_Y, dy)]
+                .into_iter()
+                .filter(|&(_, d)| d != 0)
+                .map(|(axis, d)| rel(axis, d * speed))
+                .collect();
+        if !events.is_empty() {
+            self.device()?.emit(&events)?;
+        }
+        Ok(())
+    }
+
+    /// Scrolls one step, sending whole notches to clients that don't read the hi-res wheel
+    pub fn scroll(
+        &mut self,
+        config: &MouseConfig,
+        direction: Direction,
+    ) -> Result<(), Box<dyn Error>> {
+        // The wheel counts upwards & rightwards
+        let (dx, dy) = direction.delta();
+        let (hi_res, wheel, rest) = if dy != 0 {
+            (
+                RelativeAxisCode::REL_WHEEL_HI_RES,
+                RelativeAxisCode::REL_WHEEL,
+                &mut self.wheel.1,
+            )
+        } else {
+            (
+                RelativeAxisCode::REL_HWHEEL_HI_RES,
+                RelativeAxisCode::REL_HWHEEL,
+                &mut self.wheel.0,
+            )
+        };
+        let step = (dx - dy) * config.scroll_step;
+
+        // A change of direction drops the partial notch
+        if rest.signum() == -step.signum() {
+            *rest = 0;
+        }
+        *rest += step;
+        let notches = *rest / NOTCH;
+        *rest %= NOTCH;
+
+        let mut events = vec![rel(hi_res, step)];
+        if notches != 0 {
+            events.push(rel(wheel, notches));
+        }
+        self.device()?.emit(&events)?;
+        Ok(())
+    }
+}
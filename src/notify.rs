@@ -0,0 +1,583 @@
+use serde::Deserialize;
+use std::env::var;
+use std::error::Error;
+use std::fs::{read_dir, read_to_string};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+use nix::unistd::{geteuid, seteuid, Uid};
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct NotifyConfig {
+    title: String,
+    pub enabled_text: String,
+    pub disabled_text: String,
+    /// Shown when the active layer changes, `{layer}` being its name
+    pub layer_text: String,
+    icon: String,
+    timeout_ms: i32,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        Self {
+            title: "rk".into(),
+            enabled_text: "Enabled".into(),
+            disabled_text: "Disabled".into(),
+            layer_text: "Layer: {layer}".into(),
+            icon: "input-keyboard".into(),
+            timeout_ms: 1500,
+        }
+    }
+}
+
+/// A notification to send over a freshly connected bus socket
+struct Job {
+    stream: UnixStream,
+    uid: u32,
+    config: NotifyConfig,
+    body: String,
+}
+
+/// Shows notifications on the desktop, replacing its previous popup
+///
+/// Only connecting happens on the caller's thread, as it may need to switch the effective UID,
+/// which applies to every thread. The rest, including waiting for a notification daemon that's
+/// slow or still starting, happens on a worker thread so keys never wait for it.
+#[derive(Default)]
+pub struct Notifier {
+    worker: Option<Sender<Job>>,
+    /// Set once a failure was logged at warn, until a notification gets through again
+    warned: Arc<AtomicBool>,
+}
+
+impl Notifier {
+    pub fn notify(&mut self, config: &NotifyConfig, body: &str) {
+        let (stream, uid) = match SessionBus::connect() {
+            Ok(connected) => connected,
+            Err(e) => return report(&self.warned, e),
+        };
+
+        let worker = self.worker.get_or_insert_with(|| {
+            let (sender, jobs) = channel();
+            let warned = self.warned.clone();
+            thread::spawn(move || work(jobs, warned));
+            sender
+        });
+        let job = Job {
+            stream,
+            uid,
+            config: config.clone(),
+            body: body.into(),
+        };
+        if worker.send(job).is_err() {
+            self.worker = None;
+        }
+    }
+}
+
+/// Logs the first of a run of failures at warn, the rest at debug
+fn report(warned: &AtomicBool, e: Box<dyn Error>) {
+    if warned.swap(true, Ordering::Relaxed) {
+        debug!("Notification failed: {}", e);
+    } else {
+        warn!("Notification failed: {}", e);
+    }
+}
+
+/// Sends notifications until the `Notifier` is dropped
+fn work(jobs: Receiver<Job>, warned: Arc<AtomicBool>) {
+    // ID of the last notification, so the next one replaces it
+    let mut id = 0;
+
+    for job in jobs {
+        match send(job, id) {
+            Ok(new_id) => {
+                id = new_id;
+                warned.store(false, Ordering::Relaxed);
+            }
+            Err(e) => report(&warned, e),
+        }
+    }
+}
+
+fn send(job: Job, id: u32) -> Result<u32, Box<dyn Error>> {
+    let config = &job.config;
+    let mut bus = SessionBus::new(job.stream, job.uid)?;
+    bus.call(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        "org.freedesktop.DBus",
+        "Hello",
+        &Message::new(),
+    )?;
+
+    let mut args = Message::new();
+    args.string(&config.title);
+    args.u32(id);
+    args.string(&config.icon);
+    args.string(&config.title);
+    args.string(&job.body);
+    args.empty_array(4); // actions: as
+    args.empty_array(8); // hints: a{sv}
+    args.i32(config.timeout_ms);
+    args.signature = "susssasa{sv}i";
+
+    let reply = bus.call(
+        "org.freedesktop.Notifications",
+        "/org/freedesktop/Notifications",
+        "org.freedesktop.Notifications",
+        "Notify",
+        &args,
+    )?;
+    reply.u32().ok_or_else(|| "Notify returned no ID".into())
+}
+
+/// The user whose desktop should show notifications, and their bus socket
+fn session_bus() -> Result<(u32, String), Box<dyn Error>> {
+    let bus_path = |uid: u32| format!("/run/user/{}/bus", uid);
+
+    if !geteuid().is_root() {
+        let uid = geteuid().as_raw();
+        let path = var("DBUS_SESSION_BUS_ADDRESS")
+            .ok()
+            .and_then(|a| a.strip_prefix("unix:path=").map(String::from))
+            .map(|p| p.split(',').next().unwrap_or_default().to_string())
+            .unwrap_or_else(|| bus_path(uid));
+        // Like the rk.service user, which has no desktop & can't use anyone else's bus
+        if !Path::new(&path).exists() {
+            return Err(format!(
+                "No session bus at {}, rk needs to run in the desktop session or as root to show \
+                 notifications",
+                path
+            )
+            .into());
+        }
+        return Ok((uid, path));
+    }
+
+    let uid = session_uid().ok_or("No active session found")?;
+    Ok((uid, bus_path(uid)))
+}
+
+/// The user whose desktop rk serves: its own, or when run as root the user who ran sudo,
+/// otherwise the user of the active graphical session
+pub fn session_uid() -> Option<u32> {
+    if !geteuid().is_root() {
+        return Some(geteuid().as_raw());
+    }
+    var("SUDO_UID")
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .or_else(active_session_uid)
+}
+
+/// Finds the UID of the active logind session, preferring graphical ones
+fn active_session_uid() -> Option<u32> {
+    let mut sessions: Vec<(bool, u32)> = read_dir("/run/systemd/sessions")
+        .ok()?
+        .flatten()
+        .filter_map(|entry| read_to_string(entry.path()).ok())
+        .filter_map(|content| {
+            let field = |name: &str| {
+                content
+                    .lines()
+                    .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            };
+            if field("ACTIVE") != Some("1") {
+                return None;
+            }
+            let graphical = matches!(field("TYPE"), Some("x11" | "wayland"));
+            let uid = field("UID")?.parse().ok()?;
+            Some((graphical, uid))
+        })
+        .filter(|(_, uid)| *uid != 0)
+        .collect();
+
+    sessions.sort();
+    sessions.last().map(|(_, uid)| *uid)
+}
+
+/// D-Bus message arguments, marshalled little-endian
+struct Message {
+    data: Vec<u8>,
+    signature: &'static str,
+}
+
+impl Message {
+    fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            signature: "",
+        }
+    }
+
+    fn align(&mut self, n: usize) {
+        self.data.resize(self.data.len().next_multiple_of(n), 0);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.u32(value as u32);
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value.as_bytes());
+        self.data.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.data.push(value.len() as u8);
+        self.data.extend_from_slice(value.as_bytes());
+        self.data.push(0);
+    }
+
+    /// An array with no elements, whose elements are aligned to `align`
+    fn empty_array(&mut self, align: usize) {
+        self.u32(0);
+        self.align(align);
+    }
+
+    /// A header field, a (code, variant) struct
+    fn field(&mut self, code: u8, signature: &str, value: &str) {
+        self.align(8);
+        self.data.push(code);
+        self.signature(signature);
+        match signature {
+            "g" => self.signature(value),
+            _ => self.string(value),
+        }
+    }
+}
+
+/// The body of a method reply
+struct Reply {
+    body: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Reply {
+    /// Reads the body as a single u32
+    fn u32(&self) -> Option<u32> {
+        let bytes = self.body.get(..4)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+}
+
+/// A connection to a session bus, just enough to call methods
+struct SessionBus {
+    stream: BufReader<UnixStream>,
+    serial: u32,
+}
+
+impl SessionBus {
+    /// Connects to the session bus as the session user, returning the socket & that user
+    fn connect() -> Result<(UnixStream, u32), Box<dyn Error>> {
+        let (uid, path) = session_bus()?;
+
+        // The bus authenticates the UID we connect as, so connect as the session user
+        let euid = geteuid();
+        let switch = euid.is_root() && uid != 0;
+        if switch {
+            seteuid(Uid::from_raw(uid))?;
+        }
+        let stream = UnixStream::connect(Path::new(&path));
+        if switch {
+            seteuid(euid)?;
+        }
+
+        let stream = stream.map_err(|e| format!("Failed to connect to {}: {}", path, e))?;
+        Ok((stream, uid))
+    }
+
+    /// Authenticates on a connected socket
+    fn new(stream: UnixStream, uid: u32) -> Result<Self, Box<dyn Error>> {
+        // A notification daemon may need starting by the bus first, so be patient
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+        let mut bus = Self {
+            stream: BufReader::new(stream),
+            serial: 0,
+        };
+        bus.authenticate(uid)?;
+        Ok(bus)
+    }
+
+    fn authenticate(&mut self, uid: u32) -> Result<(), Box<dyn Error>> {
+        let hex_uid: String = uid
+            .to_string()
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let stream = self.stream.get_mut();
+        stream.write_all(b"\0")?;
+        stream.write_all(format!("AUTH EXTERNAL {}\r\n", hex_uid).as_bytes())?;
+
+        let mut line = String::new();
+        self.stream.read_line(&mut line)?;
+        if !line.starts_with("OK ") {
+            return Err(format!("Authentication failed: {}", line.trim()).into());
+        }
+
+        self.stream.get_mut().write_all(b"BEGIN\r\n")?;
+        Ok(())
+    }
+
+    /// Calls a method and waits for its reply, returning the reply's body
+    fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        args: &Message,
+    ) -> Result<Reply, Box<dyn Error>> {
+        self.serial += 1;
+
+        let mut msg = Message::new();
+        // Little-endian, method call, no flags, protocol version 1
+        msg.data.extend_from_slice(&[b'l', 1, 0, 1]);
+        msg.u32(args.data.len() as u32);
+        msg.u32(self.serial);
+
+        let mut fields = Message::new();
+        fields.field(1, "o", path);
+        fields.field(2, "s", interface);
+        fields.field(3, "s", member);
+        fields.field(6, "s", destination);
+        if !args.signature.is_empty() {
+            fields.field(8, "g", args.signature);
+        }
+        msg.u32(fields.data.len() as u32);
+        msg.data.extend_from_slice(&fields.data);
+        msg.align(8);
+        msg.data.extend_from_slice(&args.data);
+
+        self.stream.get_mut().write_all(&msg.data)?;
+
+        // Skip signals & other traffic until our reply arrives
+        loop {
+            let (kind, reply_serial, reply) = self.read_message()?;
+            match (kind, reply_serial) {
+                (2, Some(serial)) if serial == self.serial => return Ok(reply),
+                (3, Some(serial)) if serial == self.serial => {
+                    return Err(format!("{} failed", member).into())
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads a message as its type, reply serial and body
+    fn read_message(&mut self) -> Result<(u8, Option<u32>, Reply), Box<dyn Error>> {
+        let mut fixed = [0u8; 16];
+        self.stream.read_exact(&mut fixed)?;
+
+        let big_endian = fixed[0] == b'B';
+        let read_u32 = |bytes: &[u8]| {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            match big_endian {
+                true => u32::from_be_bytes(bytes),
+                false => u32::from_le_bytes(bytes),
+            }
+        };
+        let kind = fixed[1];
+        let body_len = read_u32(&fixed[4..8]) as usize;
+        let fields_len = read_u32(&fixed[12..16]) as usize;
+
+        // Header fields, padded to 8 bytes, then the body
+        let padding = (8 - (16 + fields_len) % 8) % 8;
+        let mut rest = vec![0u8; fields_len + padding + body_len];
+        self.stream.read_exact(&mut rest)?;
+        let fields = &rest[..fields_len];
+
+        let mut reply_serial = None;
+        let mut pos = 0;
+        while pos < fields.len() {
+            // Fields start 16 bytes in, so aligning within them aligns within the message
+            pos = pos.next_multiple_of(8);
+            let code = fields[pos];
+            let sig_len = fields[pos + 1] as usize;
+            let sig = fields[pos + 2];
+            pos += 3 + sig_len;
+
+            match sig {
+                b'u' => {
+                    pos = pos.next_multiple_of(4);
+                    if code == 5 {
+                        reply_serial = Some(read_u32(&fields[pos..pos + 4]));
+                    }
+                    pos += 4;
+                }
+                b'g' => pos += fields[pos] as usize + 2,
+                // Strings & object paths
+                _ => {
+                    pos = pos.next_multiple_of(4);
+                    pos += 4 + read_u32(&fields[pos..pos + 4]) as usize + 1;
+                }
+            }
+        }
+
+        let body = rest[fields_len + padding..].to_vec();
+        Ok((kind, reply_serial, Reply { body, big_endian }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message from the bus, with a reply serial field if it's a reply
+    fn bus_message(kind: u8, reply_serial: Option<u32>, body: &Message) -> Vec<u8> {
+        let mut fields = Message::new();
+        if let Some(serial) = reply_serial {
+            fields.align(8);
+            fields.data.push(5);
+            fields.signature("u");
+            fields.u32(serial);
+        }
+        fields.field(7, "s", ":1.1");
+        if !body.signature.is_empty() {
+            fields.field(8, "g", body.signature);
+        }
+
+        let mut msg = Message::new();
+        msg.data.extend_from_slice(&[b'l', kind, 0, 1]);
+        msg.u32(body.data.len() as u32);
+        msg.u32(100);
+        msg.u32(fields.data.len() as u32);
+        msg.data.extend_from_slice(&fields.data);
+        msg.align(8);
+        msg.data.extend_from_slice(&body.data);
+        msg.data
+    }
+
+    #[test]
+    fn marshals_aligned_little_endian() {
+        let mut msg = Message::new();
+        msg.signature("su");
+        msg.string("ab");
+        msg.u32(7);
+        msg.i32(-1);
+        assert_eq!(
+            msg.data,
+            [
+                2, b's', b'u', 0, // signature
+                2, 0, 0, 0, b'a', b'b', 0, 0, // string & padding
+                7, 0, 0, 0, // u32
+                255, 255, 255, 255, // i32
+            ]
+        );
+    }
+
+    #[test]
+    fn marshals_header_fields_and_empty_arrays() {
+        let mut msg = Message::new();
+        msg.data.push(1);
+        msg.field(1, "o", "/a");
+        assert_eq!(
+            msg.data,
+            [1, 0, 0, 0, 0, 0, 0, 0, 1, 1, b'o', 0, 2, 0, 0, 0, b'/', b'a', 0]
+        );
+
+        // The length, then padding to where the elements would start
+        let mut msg = Message::new();
+        msg.empty_array(8);
+        assert_eq!(msg.data, [0; 8]);
+    }
+
+    #[test]
+    fn calls_skip_other_messages_until_the_reply() {
+        let (client, mut bus) = UnixStream::pair().unwrap();
+        let mut body = Message::new();
+        body.signature = "u";
+        body.u32(42);
+        bus.write_all(&bus_message(4, None, &Message::new()))
+            .unwrap();
+        bus.write_all(&bus_message(2, Some(7), &body)).unwrap();
+        bus.write_all(&bus_message(2, Some(1), &body)).unwrap();
+
+        let mut session = SessionBus {
+            stream: BufReader::new(client),
+            serial: 0,
+        };
+        let mut args = Message::new();
+        args.signature = "s";
+        args.string("hi");
+        let reply = session
+            .call(
+                "org.example",
+                "/org/example",
+                "org.example.I",
+                "Ping",
+                &args,
+            )
+            .unwrap();
+        assert_eq!(reply.u32(), Some(42));
+
+        // Little-endian method call, serial 1, body & fields lengths, then the fields
+        drop(session);
+        let mut request = Vec::new();
+        bus.read_to_end(&mut request).unwrap();
+        assert_eq!(request[..4], [b'l', 1, 0, 1]);
+        assert_eq!(request[4..8], 7u32.to_le_bytes());
+        assert_eq!(request[8..12], 1u32.to_le_bytes());
+        let fields_len = u32::from_le_bytes(request[12..16].try_into().unwrap()) as usize;
+        // The fields are padded to 8 bytes, then comes the 7 byte body
+        assert_eq!(request.len(), (16 + fields_len).next_multiple_of(8) + 7);
+        assert!(request.windows(4).any(|w| w == b"Ping"));
+        assert!(request.ends_with(&[2, 0, 0, 0, b'h', b'i', 0]));
+    }
+
+    #[test]
+    fn error_replies_fail_the_call() {
+        let (client, mut bus) = UnixStream::pair().unwrap();
+        bus.write_all(&bus_message(3, Some(1), &Message::new()))
+            .unwrap();
+
+        let mut session = SessionBus {
+            stream: BufReader::new(client),
+            serial: 0,
+        };
+        let error = session
+            .call("org.example", "/", "org.example.I", "Ping", &Message::new())
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Ping failed");
+    }
+
+    #[test]
+    fn replies_read_either_endianness() {
+        let reply = |big_endian| Reply {
+            body: vec![0, 0, 0, 42],
+            big_endian,
+        };
+        assert_eq!(reply(true).u32(), Some(42));
+        assert_eq!(reply(false).u32(), Some(42 << 24));
+        assert_eq!(
+            Reply {
+                body: vec![1],
+                big_endian: false
+            }
+            .u32(),
+            None
+        );
+    }
+}
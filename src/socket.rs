@@ -0,0 +1,18 @@
+//! The control socket's location, shared by rk & rkctl
+
+use std::env::var;
+use std::path::{Path, PathBuf};
+
+/// Where rk listens & rkctl connects, unless $RK_SOCKET is set
+pub const DEFAULT_SOCKET: &str = "/run/rk/rk.sock";
+
+/// Sockets to try in order: $RK_SOCKET, or the default & then one in $XDG_RUNTIME_DIR for
+/// users who can't create the default's directory
+pub fn socket_paths() -> Vec<PathBuf> {
+    if let Ok(path) = var("RK_SOCKET") {
+        return vec![path.into()];
+    }
+    let mut paths = vec![PathBuf::from(DEFAULT_SOCKET)];
+    paths.extend(var("XDG_RUNTIME_DIR").map(|dir| Path::new(&dir).join("rk.sock")));
+    paths
+}
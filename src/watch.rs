@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::canonicalize;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+
+/// Reports config reload requests from SIGHUP or the config file changing on disk
+pub struct ConfigWatcher {
+    signals: SignalFd,
+    inotify: Inotify,
+    file_name: Option<OsString>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGHUP);
+        mask.thread_block()?;
+        let signals = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+
+        // Editors often save by renaming a new file over the old one, so watch the directory
+        let path = Path::new(path);
+        let dir = path
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        inotify.add_watch(
+            dir,
+            AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+        )?;
+
+        Ok(Self {
+            signals,
+            inotify,
+            file_name: path.file_name().map(|n| n.to_os_string()),
+        })
+    }
+
+    /// The signal & inotify fds to wait on
+    pub fn fds(&self) -> [BorrowedFd<'_>; 2] {
+        [self.signals.as_fd(), self.inotify.as_fd()]
+    }
+
+    /// Drains pending signals and file events, returns true if a reload was requested
+    pub fn reload_requested(&mut self) -> bool {
+        let mut requested = false;
+
+        while let Ok(Some(_)) = self.signals.read_signal() {
+            requested = true;
+        }
+
+        while let Ok(events) = self.inotify.read_events() {
+            if events.is_empty() {
+                break;
+            }
+            requested |= events.iter().any(|e| e.name == self.file_name);
+        }
+
+        requested
+    }
+}
+
+pub enum DeviceChange {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+/// Reports input devices appearing in & disappearing from /dev/input
+///
+/// New nodes are reported once udev has been quiet about them for `SETTLE`, as until then they
+/// may lack their group, mode & by-id/by-path links
+pub struct DeviceWatcher {
+    inotify: Inotify,
+    /// The by-id & by-path directories, whose links point to event nodes
+    link_dirs: HashMap<WatchDescriptor, &'static str>,
+    /// Nodes waiting for udev to finish with them
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl DeviceWatcher {
+    pub const DIR: &str = "/dev/input";
+    const LINK_DIRS: [&str; 2] = ["/dev/input/by-id", "/dev/input/by-path"];
+    const SETTLE: Duration = Duration::from_millis(300);
+
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        inotify.add_watch(
+            Self::DIR,
+            AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ATTRIB | AddWatchFlags::IN_DELETE,
+        )?;
+
+        // udev creates links by renaming them into place
+        let mut link_dirs = HashMap::new();
+        for dir in Self::LINK_DIRS {
+            match inotify.add_watch(dir, AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO) {
+                Ok(wd) => {
+                    link_dirs.insert(wd, dir);
+                }
+                Err(e) => debug!("Not watching {}: {}", dir, e),
+            }
+        }
+
+        Ok(Self {
+            inotify,
+            link_dirs,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// When the next pending node settles, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.values().min().copied()
+    }
+
+    /// Drains pending events into the devices added & removed since the last call
+    pub fn changes(&mut self) -> Vec<DeviceChange> {
+        let mut changes = Vec::new();
+        let settled = Instant::now() + Self::SETTLE;
+
+        while let Ok(events) = self.inotify.read_events() {
+            if events.is_empty() {
+                break;
+            }
+
+            for event in events {
+                let Some(name) = event.name else {
+                    continue;
+                };
+
+                if let Some(dir) = self.link_dirs.get(&event.wd) {
+                    // A new link is udev still at work on the node it points to
+                    if let Ok(path) = canonicalize(Path::new(dir).join(name)) {
+                        self.pending.insert(path, settled);
+                    }
+                    continue;
+                }
+
+                let path = Path::new(Self::DIR).join(name);
+                if event.mask.contains(AddWatchFlags::IN_DELETE) {
+                    self.pending.remove(&path);
+                    changes.push(DeviceChange::Removed(path));
+                } else {
+                    // Permission changes also retry nodes that couldn't be opened before
+                    self.pending.insert(path, settled);
+                }
+            }
+        }
+
+        let now = Instant::now();
+        self.pending.retain(|path, deadline| {
+            let waiting = *deadline > now;
+            if !waiting {
+                changes.push(DeviceChange::Added(path.clone()));
+            }
+            waiting
+        });
+
+        changes
+    }
+}
+
+impl AsFd for DeviceWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inotify.as_fd()
+    }
+}
+
+/// Reports SIGINT & SIGTERM so rk can release keys and devices before exiting
+pub struct ShutdownWatcher {
+    signals: SignalFd,
+}
+
+impl ShutdownWatcher {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGINT);
+        mask.add(Signal::SIGTERM);
+        mask.thread_block()?;
+        let signals = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+
+        Ok(Self { signals })
+    }
+
+    pub fn requested(&mut self) -> bool {
+        matches!(self.signals.read_signal(), Ok(Some(_)))
+    }
+}
+
+impl AsFd for ShutdownWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.signals.as_fd()
+    }
+}